@@ -0,0 +1,125 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, decode_body, ErrorCode, FnError, join_url, prelude, query_flag, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "results": [
+        {
+            "id": "exampleid",
+            "docs": [
+                {
+                    "ok": {
+                        "_id": "exampleid",
+                        "_rev": "1-967a00dff5e02add41819138abb3284d",
+                        "task": "write docs",
+                        "done": false
+                    }
+                }
+            ]
+        },
+        {
+            "id": "missingid",
+            "docs": [
+                {
+                    "error": {
+                        "id": "missingid",
+                        "rev": "undefined",
+                        "error": "not_found",
+                        "reason": "missing"
+                    }
+                }
+            ]
+        }
+    ]
+} */
+#[derive(Deserialize, Debug)]
+struct CDBResponse {
+    results: Vec<CDBResult>
+}
+#[derive(Deserialize, Debug)]
+struct CDBResult {
+    id: String,
+    docs: Vec<CDBDoc>
+}
+#[derive(Deserialize, Debug)]
+struct CDBDoc {
+    ok: Option<sj::Value>,
+    error: Option<CDBError>
+}
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBError {
+    error: String,
+    reason: String
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes into the list of ids, e.g. ["id1", "id2"]
+    let ids: Vec<String> = sj::from_slice(&bytes)?;
+    if ids.is_empty() {
+        // Nothing to fetch
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, "Missing ids: provide a non-empty array of document ids"))
+    }
+    let docs: Vec<sj::Value> = ids.iter().map(|id| json!({"id": id})).collect();
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Fetch all requested documents in one Cloudant request
+    let uri = join_url(&config.db_url, &format!("{}/_bulk_get", &config.database));
+    let payload = json!({"docs": docs});
+    let request = config.request("POST", &uri)
+        .set("Authorization", &authorization);
+    let cdb_resp = timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &payload)))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Flatten into one entry per id: either the document, or the per-id
+    // error (e.g. a missing document), so one bad id doesn't fail the batch
+    let results: Vec<sj::Value> = cdb_data.results.into_iter()
+        .flat_map(|result| {
+            let id = result.id;
+            result.docs.into_iter().map(move |doc| match (doc.ok, doc.error) {
+                (Some(doc), _) => json!({"id": id, "doc": doc}),
+                (None, Some(error)) => json!({"id": id, "error": error.error, "reason": error.reason}),
+                (None, None) => json!({"id": id, "error": "unknown", "reason": "empty result"})
+            })
+        })
+        .collect();
+    let found = results.iter().filter(|result| result.get("doc").is_some()).count();
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "bulk_get execution complete!",
+        "found": found,
+        "results": results
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
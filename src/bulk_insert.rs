@@ -0,0 +1,202 @@
+use std::env;
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, cloudant_error, decode_body, ErrorCode, FnError, join_url, prelude, query_flag, read_cloudant_json, respond_result, send_json_body, Timings, write_quorum};
+
+
+/* Example decoded request body, replaying documents from a backup:
+{
+    "docs": [
+        {"_id": "exampleid", "_rev": "1-967a00dff5e02add41819138abb3284d", "task": "write docs", "done": false}
+    ],
+    "new_edits": false
+}
+`new_edits` defaults to true. With `false`, Cloudant stores each document
+under the `_rev` it carries instead of generating a new one, so every
+document must have a `_rev`. */
+#[derive(Deserialize, Serialize, Debug)]
+struct ICFRequestBody {
+    docs: Vec<sj::Map<String, sj::Value>>,
+    #[serde(default = "default_new_edits")]
+    new_edits: bool
+}
+
+fn default_new_edits() -> bool {
+    true
+}
+
+/* Example HTTP response from IBM Cloudant (`_bulk_docs`):
+[
+    {"id": "exampleid", "ok": true, "rev": "1-967a00dff5e02add41819138abb3284d"},
+    {"id": "otherid", "error": "conflict", "reason": "Document update conflict."}
+]
+With `new_edits: false`, only the documents that failed are listed. */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBBulkResult {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>
+}
+
+// Documents sent per `_bulk_docs` request, overridable via `BULK_CHUNK_SIZE`,
+// keeping large imports under Cloudant's request size limit
+const BULK_CHUNK_SIZE: usize = 500;
+
+fn bulk_chunk_size() -> usize {
+    env::var("BULK_CHUNK_SIZE").ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&value| value > 0)
+        .unwrap_or(BULK_CHUNK_SIZE)
+}
+
+// Documents that can't be replayed with `new_edits: false`, by position
+fn missing_revs(body: &ICFRequestBody) -> Vec<usize> {
+    if body.new_edits {
+        return Vec::new()
+    }
+    body.docs.iter().enumerate()
+        .filter(|(_, doc)| !matches!(doc.get("_rev"), Some(sj::Value::String(rev)) if !rev.is_empty()))
+        .map(|(n, _)| n)
+        .collect()
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes
+    let bulk: ICFRequestBody = sj::from_slice(&bytes)?;
+    if bulk.docs.is_empty() {
+        // Nothing to insert
+        // Return error message
+        return Err(FnError::new(ErrorCode::ValidationError, "Failure validating documents: expected at least one document"))
+    }
+    let missing = missing_revs(&bulk);
+    if !missing.is_empty() {
+        // Replayed documents must carry their revision
+        // Return error message
+        return Err(FnError::new(ErrorCode::ValidationError, format!("Failure validating documents: new_edits=false requires a _rev on every document, missing at {:?}", missing)))
+    }
+
+    // Optional `w` write quorum, see `write_quorum`
+    let w = write_quorum(&i.__ow_query)
+        .map_err(|msg| FnError::new(ErrorCode::BadRequest, msg))?;
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Write the documents chunk by chunk, with the same token. A failing
+    // chunk stops the import: the results of the chunks before it are kept
+    // and reported with it, later chunks are not attempted.
+    let uri = join_url(&config.db_url, &format!("{}/_bulk_docs", &config.database));
+    let mut request = config.request("POST", &uri)
+        .set("Authorization", &authorization);
+    if let Some(w) = w {
+        request = request.query("w", &w.to_string());
+    }
+    let chunk_size = bulk_chunk_size();
+    let chunks = bulk.docs.chunks(chunk_size).count();
+    let mut cdb_data: Vec<CDBBulkResult> = Vec::new();
+    for (n, docs) in bulk.docs.chunks(chunk_size).enumerate() {
+        let payload = json!({"docs": docs, "new_edits": bulk.new_edits});
+        let (code, failure) = match timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &payload))) {
+            Ok(res) => match read_cloudant_json::<Vec<CDBBulkResult>>(res) {
+                Ok(res) => {
+                    cdb_data.extend(res);
+                    continue
+                },
+                Err(msg) => (ErrorCode::CloudantError, json!({"code": ErrorCode::CloudantError, "msg": msg}))
+            },
+            Err(err) => {
+                let err = cloudant_error(err);
+                let code = ErrorCode::from_status(err.status);
+                (code, json!({
+                    "code": code,
+                    "msg": err.msg,
+                    "status": err.status,
+                    "cloudant_error": err.body
+                }))
+            }
+        };
+        // Chunk failed, report it next to the results written so far
+        let (msg, failed) = failed_chunk(n, chunks, chunk_size, docs.len(), failure);
+        let mut fields = json!({"results": cdb_data, "failed_chunk": failed});
+        if query_flag(&i.__ow_query, "timings") {
+            fields["timings"] = timings.to_json();
+        }
+        return Err(FnError::detailed("200 OK", code, msg, fields))
+    }
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "bulk_insert execution complete!",
+        "new_edits": bulk.new_edits,
+        "chunks": chunks,
+        "results": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+// Message and `failed_chunk` report of the `n`th (0-based) chunk failing
+// with `failure`. Chunks are numbered from 1 in both, `first_doc` is the
+// 0-based index of the chunk's first document in the request's `docs`.
+fn failed_chunk(n: usize, chunks: usize, chunk_size: usize, docs: usize, failure: sj::Value) -> (String, sj::Value) {
+    let msg = format!("Failure writing chunk {} of {}: {}", n + 1, chunks, failure["msg"].as_str().unwrap_or_default());
+    (msg, json!({
+        "chunk": n + 1,
+        "first_doc": n * chunk_size,
+        "docs": docs,
+        "error": failure
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_revs_without_new_edits() {
+        let bulk: ICFRequestBody = sj::from_value(json!({
+            "docs": [{"_id": "a", "_rev": "1-abc"}, {"_id": "b"}, {"_id": "c", "_rev": ""}],
+            "new_edits": false
+        })).unwrap();
+        assert_eq!(missing_revs(&bulk), vec![1, 2]);
+
+        let bulk: ICFRequestBody = sj::from_value(json!({"docs": [{"_id": "b"}]})).unwrap();
+        assert!(bulk.new_edits);
+        assert!(missing_revs(&bulk).is_empty());
+    }
+
+    #[test]
+    fn numbers_the_failed_chunk_from_one() {
+        let failure = json!({"code": ErrorCode::CloudantError, "msg": "Cloudant is down"});
+        let (msg, failed) = failed_chunk(2, 4, 100, 37, failure.clone());
+        assert_eq!(msg, "Failure writing chunk 3 of 4: Cloudant is down");
+        assert_eq!(failed, json!({"chunk": 3, "first_doc": 200, "docs": 37, "error": failure}));
+    }
+}
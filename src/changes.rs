@@ -0,0 +1,150 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, ErrorCode, FnError, join_url, prelude, query_flag, query_param, query_params, read_cloudant_json, respond_result, Timings};
+
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "results": [
+        {
+            "seq": "1-g1AAAAGbeJzLYWBgYMlgTmGQS0lKzi9KdUhJMtbLTczPK0lNzMnMS9dLzskvTUnMK9HLSy3JAapjSmQIsv___z8rkQGP2iQFIJlkD1KuiVO5A0h5PKpyYyKUJ0CU1xNWnscCJBkagBRQh_lghYEEFS6AKNxPUOEBiML7hM1sgCicT1jhA4jC_-D3EfZOAwA",
+            "id": "exampleid",
+            "changes": [{"rev": "1-967a00dff5e02add41819138abb3284d"}]
+        }
+    ],
+    "last_seq": "1-g1AAAAGbeJzLYWBgYMlgTmGQS0lKzi9KdUhJMtbLTczPK0lNzMnMS9dLzskvTUnMK9HLSy3JAapjSmQIsv___z8rkQGP2iQFIJlkD1KuiVO5A0h5PKpyYyKUJ0CU1xNWnscCJBkagBRQh_lghYEEFS6AKNxPUOEBiML7hM1sgCicT1jhA4jC_-D3EfZOAwA",
+    "pending": 0
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    results: Vec<sj::Value>,
+    last_seq: sj::Value,
+    pending: Option<i64>
+}
+
+// Serverless functions can't hold a continuous feed open, so every request
+// is a bounded `feed=normal` request of at most `limit` changes
+const DEFAULT_LIMIT: u32 = 100;
+
+// Query params read by this function or the shared code, never forwarded
+// as filter args
+const OWN_PARAMS: [&str; 7] = ["since", "limit", "filter", "db", "timings", "verbose", "pretty"];
+
+// A named filter is `ddoc/name`, the filter `name` of the design document
+// `_design/ddoc`. Cloudant's built-in filters (`_doc_ids`, `_selector`,
+// `_design`, `_view`) start with `_` and are taken as is.
+fn validate_filter(filter: &str) -> Result<(), String> {
+    if filter.starts_with('_') && !filter.contains('/') {
+        return Ok(())
+    }
+    match filter.split_once('/') {
+        Some((ddoc, name)) if !ddoc.is_empty() && !name.is_empty() && !name.contains('/') => Ok(()),
+        _ => Err(format!("Invalid filter query param: {}, expected ddoc/name", filter))
+    }
+}
+
+// The args of a filter: every query param this function doesn't use
+// itself, passed on as is, e.g. `owner=jane` for `req.query.owner`
+fn filter_args(query: &str) -> Vec<(String, String)> {
+    query_params(query).into_iter()
+        .filter(|(name, _)| !OWN_PARAMS.contains(&name.as_str()))
+        .collect()
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Read the feed position and page size from the query string
+    let since = query_param(&i.__ow_query, "since").unwrap_or_else(|| "0".to_string());
+    let limit = match query_param(&i.__ow_query, "limit") {
+        None => DEFAULT_LIMIT,
+        Some(limit) => match limit.parse::<u32>() {
+            Ok(limit) if limit > 0 => limit,
+            _ => {
+                // Limit is not a positive integer
+                // Return error message
+                return Err(FnError::new(ErrorCode::BadRequest, format!("Invalid limit query param: {}", limit)))
+            }
+        }
+    };
+
+    // Optional server-side filter, with its args
+    let filter = query_param(&i.__ow_query, "filter").filter(|filter| !filter.is_empty());
+    if let Some(filter) = &filter {
+        validate_filter(filter).map_err(|msg| FnError::new(ErrorCode::BadRequest, msg))?;
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Read the Cloudant changes feed
+    let uri = join_url(&config.db_url, &format!("{}/_changes", &config.database));
+    let mut request = config.request("GET", &uri)
+        .query("feed", "normal")
+        .query("since", &since)
+        .query("limit", &limit.to_string())
+        .set("Authorization", &authorization);
+    if let Some(filter) = &filter {
+        request = request.query("filter", filter);
+        for (name, value) in filter_args(&i.__ow_query) {
+            request = request.query(&name, &value);
+        }
+    }
+    let cdb_resp = timings.db(|| cloudant_call("GET", || request.clone().call()))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "changes execution complete!",
+        "results": cdb_data.results,
+        "last_seq": cdb_data.last_seq,
+        "pending": cdb_data.pending
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_filter_names() {
+        assert!(validate_filter("app/by_owner").is_ok());
+        assert!(validate_filter("_selector").is_ok());
+        assert!(validate_filter("by_owner").is_err());
+        assert!(validate_filter("app/").is_err());
+        assert!(validate_filter("/by_owner").is_err());
+        assert!(validate_filter("app/by/owner").is_err());
+    }
+
+    #[test]
+    fn forwards_unknown_params_as_filter_args() {
+        let args = filter_args("filter=app%2Fby_owner&since=now&owner=jane&timings=true&status=open");
+        assert_eq!(args, vec![
+            ("owner".to_string(), "jane".to_string()),
+            ("status".to_string(), "open".to_string())
+        ]);
+    }
+}
@@ -0,0 +1,74 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{admin_enabled, cloudant_call, ErrorCode, FnError, join_url, path_segment, prelude, query_flag, query_param, read_cloudant_json, respond_result, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (202 Accepted, compaction runs
+in the background):
+{
+    "ok": true
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    ok: bool
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Compaction is an admin operation, opted into per deployment
+    if !admin_enabled() {
+        // Admin operations not enabled for this function
+        // Return error message
+        return Err(FnError::new(ErrorCode::Forbidden, "Refusing to compact: set ADMIN_ENABLED=true to allow admin operations"))
+    }
+
+    // Optional design document name: compact its view indexes instead of
+    // the database itself. The name stays a single path segment of the
+    // database, see `path_segment`.
+    let path = match query_param(&i.__ow_query, "design") {
+        Some(design) if !design.is_empty() => {
+            let design = path_segment(&design).map_err(|msg| FnError::new(ErrorCode::BadRequest, msg))?;
+            format!("{}/_compact/{}", &config.database, design)
+        },
+        _ => format!("{}/_compact", &config.database)
+    };
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Start the compaction
+    let uri = join_url(&config.db_url, &path);
+    let cdb_resp = timings.db(|| cloudant_call("POST", || config.request("POST", &uri)
+        .set("Authorization", &authorization)
+        .call()))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "compact execution complete!",
+        "ok": cdb_data.ok
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
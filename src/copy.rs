@@ -0,0 +1,118 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, current_rev, doc_url, ErrorCode, FnError, prelude, query_flag, query_param, read_cloudant_json, respond_result, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (COPY):
+{
+    "id": "copyid",
+    "ok": true,
+    "rev": "1-967a00dff5e02add41819138abb3284d"
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    id: String,
+    ok: bool,
+    rev: String
+}
+
+// `Destination` header of a COPY: the target id, plus the target's current
+// rev when it already exists and gets overwritten
+fn destination(target: &str, rev: Option<&str>) -> String {
+    match rev {
+        Some(rev) => format!("{}?rev={}", target, rev),
+        None => target.to_string()
+    }
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Read the source and target document ids from the query string
+    let (id, target) = match (query_param(&i.__ow_query, "id"), query_param(&i.__ow_query, "destination")) {
+        (Some(id), Some(target)) if !id.is_empty() && !target.is_empty() => (id, target),
+        _ => {
+            // Document ids not provided
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Missing id or destination query param"))
+        }
+    };
+    if target.contains('?') {
+        // The Destination header can't carry it, `?` starts the rev
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, "Invalid destination query param: ? is not allowed in a destination id"))
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Cloudant refuses to copy over an existing document unless the
+    // Destination carries its rev: `destination_rev` passes it explicitly,
+    // `overwrite=true` looks it up
+    let target_uri = doc_url(&config.db_url, &config.database, &target);
+    let mut rev = query_param(&i.__ow_query, "destination_rev").filter(|rev| !rev.is_empty());
+    if rev.is_none() && query_flag(&i.__ow_query, "overwrite") {
+        rev = timings.db(|| current_rev(config.request("HEAD", &target_uri).set("Authorization", &authorization)));
+    }
+
+    // Copy the document server-side, no need to fetch it first
+    let uri = doc_url(&config.db_url, &config.database, &id);
+    let cdb_resp = match timings.db(|| cloudant_call("COPY", || config.request("COPY", &uri)
+        .set("Authorization", &authorization)
+        .set("Destination", &destination(&target, rev.as_deref()))
+        .call())) {
+            Ok(res) => res,
+            Err(err @ ureq::Error::Status(409, _)) => {
+                // Destination already exists, or its rev is stale: hand out
+                // the current one to retry with
+                let rev = current_rev(config.request("HEAD", &target_uri).set("Authorization", &authorization));
+                return Err(FnError::conflict(err, rev))
+            },
+            Err(err) => {
+                // Failure querying Cloudant, report its error body
+                return Err(FnError::from(err))
+            }
+        };
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "copy execution complete!",
+        "id": cdb_data.id,
+        "rev": cdb_data.rev
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_rev_to_destination() {
+        assert_eq!(destination("copyid", None), "copyid");
+        assert_eq!(destination("copyid", Some("1-abc")), "copyid?rev=1-abc");
+    }
+}
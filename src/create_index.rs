@@ -0,0 +1,76 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, decode_body, ErrorCode, FnError, join_url, prelude, query_flag, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "result": "created",
+    "id": "_design/a5f4711fc9448864a13c81dc71e660b524d7410c",
+    "name": "done-index"
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    result: String,
+    id: String,
+    name: String
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes into an index definition, e.g.
+    // {"index": {"fields": ["done"]}, "name": "done-index", "type": "json"}
+    let index: sj::Value = sj::from_slice(&bytes)?;
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Create the index on the Cloudant database
+    let uri = join_url(&config.db_url, &format!("{}/_index", &config.database));
+    let request = config.request("POST", &uri)
+        .set("Authorization", &authorization);
+    let cdb_resp = timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &index)))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Cloudant answers 200 with `"result": "exists"` when an identical
+    // index is already defined, instead of `"result": "created"`
+    let created = cdb_data.result == "created";
+    let msg = if created {
+        "create_index execution complete!"
+    } else {
+        "create_index execution complete, index already exists!"
+    };
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": msg,
+        "created": created,
+        "index": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
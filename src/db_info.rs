@@ -0,0 +1,117 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, ErrorCode, FnError, join_url, prelude, query_flag, read_cloudant_json, respond_result, Timings};
+
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "db_name": "todos",
+    "update_seq": "12-g1AAAAFTeJzLYWBgYMlgTmFQ...",
+    "sizes": {
+        "file": 380108,
+        "external": 1430,
+        "active": 33457
+    },
+    "purge_seq": 0,
+    "doc_del_count": 1,
+    "doc_count": 11,
+    "disk_format_version": 8,
+    "compact_running": false,
+    "cluster": {"q": 16, "n": 3, "w": 2, "r": 2},
+    "instance_start_time": "0"
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    db_name: String,
+    update_seq: String,
+    sizes: CDBSizes,
+    purge_seq: sj::Value,
+    doc_del_count: u64,
+    doc_count: u64,
+    disk_format_version: u32,
+    compact_running: bool,
+    cluster: CDBCluster,
+    instance_start_time: String
+}
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBSizes {
+    file: u64,
+    external: u64,
+    active: u64
+}
+// Shard layout: `q` shards, `n` replicas, `w`/`r` write and read quorums
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBCluster {
+    q: u32,
+    n: u32,
+    w: u32,
+    r: u32
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Fetch the database info document
+    let uri = join_url(&config.db_url, &config.database);
+    let cdb_resp = timings.db(|| cloudant_call("GET", || config.request("GET", &uri)
+        .set("Authorization", &authorization)
+        .call()))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "db_info execution complete!",
+        "info": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_info_document() {
+        let info: CDBResponse = sj::from_str(r#"{
+            "db_name": "todos",
+            "update_seq": "12-g1AAAAFTeJzLYWBgYMlgTmFQ",
+            "sizes": {"file": 380108, "external": 1430, "active": 33457},
+            "purge_seq": 0,
+            "doc_del_count": 1,
+            "doc_count": 11,
+            "disk_format_version": 8,
+            "compact_running": false,
+            "cluster": {"q": 16, "n": 3, "w": 2, "r": 2},
+            "instance_start_time": "0",
+            "props": {}
+        }"#).unwrap();
+        assert_eq!(info.doc_count, 11);
+        assert_eq!(info.sizes.active, 33457);
+        assert_eq!(info.cluster.q, 16);
+    }
+}
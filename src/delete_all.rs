@@ -0,0 +1,116 @@
+use std::env;
+use serde::Deserialize;
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, ErrorCode, FnError, join_url, prelude, query_flag, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (`_all_docs`):
+{
+    "offset": 0,
+    "rows": [
+        {
+            "id": "exampleid",
+            "key": "exampleid",
+            "value": {
+                "rev": "1-967a00dff5e02add41819138abb3284d"
+            }
+        }
+    ],
+    "total_rows": 1
+} */
+#[derive(Deserialize, Debug)]
+struct CDBAllDocs {
+    rows: Vec<CDBRecord>
+}
+#[derive(Deserialize, Debug)]
+struct CDBRecord {
+    id: String,
+    value: CDBValue
+}
+#[derive(Deserialize, Debug)]
+struct CDBValue {
+    rev: String
+}
+
+/* Example HTTP response from IBM Cloudant (`_bulk_docs`):
+[
+    {"id": "exampleid", "ok": true, "rev": "2-7051cbe5c8faecd085a3fa619e6e6337"},
+    {"id": "otherid", "error": "conflict", "reason": "Document update conflict."}
+] */
+#[derive(Deserialize, Debug)]
+struct CDBBulkResult {
+    ok: Option<bool>
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Emptying a database needs two guards: the `confirm=true` query param
+    // and the `PURGE_ENABLED=1` env var of the deployed function
+    let purge_enabled = env::var("PURGE_ENABLED").map(|v| v == "1").unwrap_or(false);
+    if !query_flag(&i.__ow_query, "confirm") || !purge_enabled {
+        // Missing safety guard
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, "Refusing to delete all documents: requires confirm=true and PURGE_ENABLED=1"))
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // List the ids and revs of all documents
+    let uri = join_url(&config.db_url, &format!("{}/_all_docs", &config.database));
+    let cdb_resp = timings.db(|| cloudant_call("GET", || config.request("GET", &uri)
+        .set("Authorization", &authorization)
+        .call()))?;
+
+    // Deserialize Cloudant response
+    let all_docs = read_cloudant_json::<CDBAllDocs>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Mark every document deleted; design documents are kept, so views
+    // and indexes survive the reset
+    let docs: Vec<sj::Value> = all_docs.rows.iter()
+        .filter(|row| !row.id.starts_with("_design/"))
+        .map(|row| json!({"_id": row.id, "_rev": row.value.rev, "_deleted": true}))
+        .collect();
+
+    // Delete them all in one `_bulk_docs` request
+    let uri = join_url(&config.db_url, &format!("{}/_bulk_docs", &config.database));
+    let payload = json!({"docs": docs});
+    let request = config.request("POST", &uri)
+        .set("Authorization", &authorization);
+    let cdb_resp = timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &payload)))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<Vec<CDBBulkResult>>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Count the documents Cloudant actually deleted
+    let deleted = cdb_data.iter().filter(|res| res.ok == Some(true)).count();
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "delete_all execution complete!",
+        "deleted": deleted,
+        "failed": docs.len() - deleted
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
@@ -0,0 +1,146 @@
+use serde::Deserialize;
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, decode_body, ErrorCode, FnError, join_url, prelude, query_flag, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+// Matching documents fetched and deleted per `_find` / `_bulk_docs` round
+const PAGE_SIZE: usize = 200;
+
+/* Example decoded request body:
+{
+    "selector": {"done": true}
+} */
+#[derive(Deserialize, Debug)]
+struct ICFRequestBody {
+    selector: sj::Value
+}
+
+/* Example HTTP response from IBM Cloudant (`_find` with `fields: ["_id", "_rev"]`):
+{
+    "docs": [
+        {"_id": "exampleid", "_rev": "1-967a00dff5e02add41819138abb3284d"}
+    ],
+    "bookmark": "g1AAAABweJzLYWBgYMpgSmHgKy5JLCrJTq2MT8lPzkzJBYpzWBoaWRqC5TnQlEAAAABhCAM"
+} */
+#[derive(Deserialize, Debug)]
+struct CDBFindResponse {
+    docs: Vec<CDBDocument>,
+    bookmark: Option<String>
+}
+#[derive(Deserialize, Debug)]
+struct CDBDocument {
+    _id: String,
+    _rev: String
+}
+
+/* Example HTTP response from IBM Cloudant (`_bulk_docs`):
+[
+    {"id": "exampleid", "ok": true, "rev": "2-7051cbe5c8faecd085a3fa619e6e6337"},
+    {"id": "otherid", "error": "conflict", "reason": "Document update conflict."}
+] */
+#[derive(Deserialize, Debug)]
+struct CDBBulkResult {
+    ok: Option<bool>
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Mass deletes need an explicit `confirm=true` query param
+    if !query_flag(&i.__ow_query, "confirm") {
+        // Missing safety guard
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, "Refusing to delete documents by query: requires confirm=true"))
+    }
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes
+    let query: ICFRequestBody = sj::from_slice(&bytes)?;
+    if !query.selector.is_object() {
+        // Mango selectors are JSON objects
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, "Invalid selector: expected a JSON object"))
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Page through the matching documents with `_find` bookmarks, deleting
+    // each page in one `_bulk_docs` request before fetching the next one. A
+    // failing request stops the deletion, reported with the counts of the
+    // pages done before it.
+    let find_uri = join_url(&config.db_url, &format!("{}/_find", &config.database));
+    let bulk_uri = join_url(&config.db_url, &format!("{}/_bulk_docs", &config.database));
+    let mut bookmark: Option<String> = None;
+    let (mut deleted, mut failed) = (0, 0);
+    let mut delete = || -> Result<(), FnError> { loop {
+        // Fetch the ids and revs of the next page of matches
+        let mut find = json!({
+            "selector": query.selector,
+            "fields": ["_id", "_rev"],
+            "limit": PAGE_SIZE
+        });
+        if let Some(bookmark) = &bookmark {
+            find["bookmark"] = json!(bookmark);
+        }
+        let request = config.request("POST", &find_uri)
+            .set("Authorization", &authorization);
+        let cdb_resp = timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &find)))?;
+        let page = read_cloudant_json::<CDBFindResponse>(cdb_resp)
+            .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+        if page.docs.is_empty() {
+            return Ok(())
+        }
+
+        // Mark the page's documents deleted
+        let docs: Vec<sj::Value> = page.docs.iter()
+            .map(|doc| json!({"_id": doc._id, "_rev": doc._rev, "_deleted": true}))
+            .collect();
+        let payload = json!({"docs": docs});
+        let request = config.request("POST", &bulk_uri)
+            .set("Authorization", &authorization);
+        let cdb_resp = timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &payload)))?;
+        let results = read_cloudant_json::<Vec<CDBBulkResult>>(cdb_resp)
+            .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+        let ok = results.iter().filter(|res| res.ok == Some(true)).count();
+        deleted += ok;
+        failed += docs.len() - ok;
+
+        // A short page is the last one
+        if page.docs.len() < PAGE_SIZE {
+            return Ok(())
+        }
+        bookmark = page.bookmark;
+    }};
+    if let Err(err) = delete() {
+        return Err(err.with_fields(json!({"deleted": deleted, "failed": failed})))
+    }
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "delete_by_query execution complete!",
+        "deleted": deleted,
+        "failed": failed
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
@@ -0,0 +1,68 @@
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, doc_url, ErrorCode, etag_rev, FnError, prelude, query_flag, query_param, respond_result, Timings};
+
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Read the document id from the query string
+    let id = match query_param(&i.__ow_query, "id") {
+        Some(id) if !id.is_empty() => id,
+        _ => {
+            // Document id not provided
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Missing id query param"))
+        }
+    };
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // HEAD the document, so only its headers are transferred
+    let uri = doc_url(&config.db_url, &config.database, &id);
+    let rev = match timings.db(|| cloudant_call("HEAD", || config.request("HEAD", &uri)
+        .set("Authorization", &authorization)
+        .call())) {
+            Ok(res) => match res.header("ETag") {
+                Some(etag) => Some(etag_rev(etag)),
+                None => {
+                    // Document found, but its rev is missing
+                    // Return error message
+                    return Err(FnError::new(ErrorCode::CloudantError, "Failure reading Cloudant response: missing ETag header"))
+                }
+            },
+            Err(ureq::Error::Status(404, _)) => None,
+            Err(err) => {
+                // Failure querying Cloudant, report its error body
+                return Err(FnError::from(err))
+            }
+        };
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "exists execution complete!",
+        "exists": rev.is_some()
+    });
+    if let Some(rev) = rev {
+        body["rev"] = json!(rev);
+    }
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
@@ -1,237 +1,376 @@
-use std::{env, process};
-use serde::{Serialize, Deserialize};
-use serde_json::{self as sj};
-use ureq::json as json;
-//use base64::decode;
-
-
-/* Example Raw HTTP payload from IBM Cloud Functions:
-{
-    "<custom_params>": <value>,
-    ...
-    "__ow_method": "post",
-    "__ow_query": "name=Jane",
-    "__ow_body": "eyJuYW1lIjoiSmFuZSJ9",
-    "__ow_headers": {
-    "accept": "*\/\*",
-    "connection": "close",
-    "content-length": "15",
-    "content-type": "application/json",
-    "host": "172.17.0.1",
-    "user-agent": "curl/7.43.0"
-    },
-    "__ow_path": ""
-} */
-#[derive(Deserialize)]
-struct ICFRawInput {
-    iam_apikey: String,
-    db_url: String,
-    database: String,
-    __ow_body: String,
-    __ow_headers: sj::Value,
-    __ow_method: String,
-    __ow_path: String,
-    __ow_query: String
-}
-
-/* Example HTTP response from IBM Cloud IAM:
-{
-    "access_token": "<omitted>",
-    "refresh_token": "not_supported",
-    "token_type": "Bearer",
-    "expires_in": 3600,
-    "expiration": 1616239535,
-    "scope": "ibm openid"
-} */
-#[derive(Deserialize, Debug)]
-struct IAMResponse {
-    access_token: String,
-    refresh_token: String,
-    token_type: String,
-    expires_in: i32,
-    expiration: i32,
-    scope: String
-}
-
-/* Example HTTP response from IBM Cloudant:
-{
-    "offset": 0,
-    "rows": [
-        {
-            "doc": {
-                "_id": "exampleid",
-                "_rev": "1-967a00dff5e02add41819138abb3284d"
-            },
-            "id": "exampleid",
-            "key": "exampleid",
-            "value": {
-                "rev": "1-967a00dff5e02add41819138abb3284d"
-            }
-        }
-    ],
-    "total_rows": 1
-} */
-#[derive(Deserialize, Serialize, Debug)]
-struct CDBResponse {
-    offset: i32,
-    rows: Vec<CDBRecord>,
-    total_rows: i32
-}
-#[derive(Deserialize, Serialize, Debug)]
-struct CDBRecord {
-    id: String,
-    key: String,
-    value: CDBValue
-}
-#[derive(Deserialize, Serialize, Debug)]
-struct CDBValue {
-    rev: String,
-}
-
-
-fn main() {
-    
-    // Read input arguments as a vector of Strings
-    let args: Vec<String> = env::args().collect();
-    println!("{:?}", &args);
-
-    // Use serde_json to deserialize a &str into a Payload struct
-    // NOTE: The `args[0]` element is traditionally the path of
-    // the executable, but it can be set to arbitrary text, and
-    // may not even exist. This means this property should not be 
-    // relied upon for security purposes.
-    let i: ICFRawInput = match sj::from_str(&args[1]) {
-        Ok(res) => res,
-        Err(err) => {
-            // Failed to parse input into expected Rust struct
-            // Return error message
-            let o = json!({
-                "statusCode": "200 OK",
-                "body": {
-                    "err": true,
-                    "msg": format!("Failure parsing raw HTTP request: {}", err)
-                }
-            });
-            // The serverless function output is pushed to stdout
-            println!("{}", sj::to_string(&o).unwrap());
-            // The process is killed through the OS exitcode
-            process::exit(exitcode::OK)
-        }
-    };
-
-    // Request IAM token from IBM Cloud
-    /* Reference request:
-        curl -X POST \
-            "https://iam.cloud.ibm.com/identity/token" \
-            --header 'Content-Type: application/x-www-form-urlencoded' \
-            --header 'Accept: application/json' \
-            --data-urlencode 'grant_type=urn:ibm:params:oauth:grant-type:apikey' \
-            --data-urlencode 'apikey={api_key}'
-    */
-    let iam_resp = match ureq::post("https://iam.cloud.ibm.com/identity/token")
-        .set("Content-Type", "application/x-www-form-urlencoded")
-        .set("Accept", "application/json")
-        .send_form(&[
-            ("apikey", &i.iam_apikey),
-            ("grant_type", "urn:ibm:params:oauth:grant-type:apikey")
-        ]) {
-            Ok(iam_resp) => iam_resp,
-            Err(_) => {
-                // Failure requesting IAM token
-                // Return error message
-                let o = json!({
-                    "statusCode": "200 OK",
-                    "body": {
-                        "err": true,
-                        "msg": format!("Failure requesting IAM token")
-                    }
-                });
-                // The serverless function output is pushed to stdout
-                println!("{}", sj::to_string(&o).unwrap());
-                // The process is killed through the OS exitcode
-                process::exit(exitcode::OK)
-            }
-        };
-
-    // Deserialize IAM response
-    let iam_token = match iam_resp.into_json::<IAMResponse>() {
-        Ok(iam_data) => {
-            //println!("{:?}", iam_data);
-            iam_data.access_token
-        },
-        Err(err) => {
-            // Failure deserializing IAM response
-            // Return error message
-            let o = json!({
-                "statusCode": "200 OK",
-                "body": {
-                    "err": true,
-                    "msg": format!("Failure deserializing IAM response: {}", err)
-                }
-            });
-            // The serverless function output is pushed to stdout
-            println!("{}", sj::to_string(&o).unwrap());
-            // The process is killed through the OS exitcode
-            process::exit(exitcode::OK)
-        }
-    };
-
-    // Query Cloudant the database
-    let uri = format!("{}/{}/_all_docs", &i.db_url, &i.database);
-    let bearer = format!("Bearer {}", &iam_token);
-    let cdb_resp = match ureq::get(&uri)
-        .set("Authorization", &bearer)
-        .call() {
-            Ok(res) => res,
-            Err(err) => {
-                // Failure querying Cloudant
-                // Return error message
-                let o = json!({
-                    "statusCode": "200 OK",
-                    "body": {
-                        "err": true,
-                        "msg": format!("Failure querying Cloudant: {}", err)
-                    }
-                });
-                // The serverless function output is pushed to stdout
-                println!("{}", sj::to_string(&o).unwrap());
-                // The process is killed through the OS exitcode
-                process::exit(exitcode::OK)
-            }
-        };
-
-    // Deserialize Cloudant response
-    let cdb_data = match cdb_resp.into_json::<CDBResponse>() {
-        Ok(res) => res,
-        Err(err) => {
-            // Failure deserializing IAM response
-            // Return error message
-            let o = json!({
-                "statusCode": "200 OK",
-                "body": {
-                    "err": true,
-                    "msg": format!("Failure deserializing IAM response: {}", err)
-                }
-            });
-            // The serverless function output is pushed to stdout
-            println!("{}", sj::to_string(&o).unwrap());
-            // The process is killed through the OS exitcode
-            process::exit(exitcode::OK)
-        }
-    };
-
-    // Build output struct
-    let o = json!({
-        "statusCode": "200 OK",
-        "body": json!({
-            "err": false,
-            "msg": "fetch_all execution complete!",
-            "data": cdb_data
-        })
-    });
-
-    // The wsk function output is pushed to stdout
-    println!("{}", sj::to_string(&o).unwrap());
-    // The process is killed through the OS exitcode
-    process::exit(exitcode::OK)
-}
+use std::{env, fmt, io::Write};
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, decode_optional_body, ErrorCode, FnError, join_url, json_string_param, prelude, query_flag, query_param, read_cloudant_json, respond_fn_err, respond_ok, respond_ok_streamed, send_json_body, Timings};
+
+
+/* Example decoded request body, to only fetch the rows of some ids:
+{
+    "keys": ["exampleid", "otherid"]
+}
+The body is optional: without `keys`, all documents are listed. */
+#[derive(Deserialize, Debug)]
+struct ICFRequestBody {
+    keys: Option<Vec<String>>
+}
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "offset": 0,
+    "rows": [
+        {
+            "doc": {
+                "_id": "exampleid",
+                "_rev": "1-967a00dff5e02add41819138abb3284d"
+            },
+            "id": "exampleid",
+            "key": "exampleid",
+            "value": {
+                "rev": "1-967a00dff5e02add41819138abb3284d"
+            }
+        }
+    ],
+    "total_rows": 1
+}
+It is not deserialized as a whole but streamed row by row, see `stream_rows`. */
+// Rows requested by `keys` that don't exist come back as
+// {"key": "missingid", "error": "not_found"}, without `id` and `value`
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBRecord {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<CDBValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>
+}
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBValue {
+    rev: String,
+}
+
+// Copy the `_all_docs` response from `reader` to `out` one row at a time,
+// so large results are never held in memory. `rows` come first in the
+// output, `offset` and `total_rows` after them. Rows beyond `cap` are
+// dropped and reported as `truncated`.
+fn stream_rows<R: std::io::Read>(reader: R, out: &mut dyn Write, cap: usize) -> Result<bool, String> {
+    let mut de = sj::Deserializer::from_reader(reader);
+    let truncated = StreamResponse { out, cap }.deserialize(&mut de)
+        .map_err(|err| format!("Failure streaming Cloudant response: {}", err))?;
+    de.end().map_err(|err| format!("Failure streaming Cloudant response: {}", err))?;
+    Ok(truncated)
+}
+
+struct StreamResponse<'w> {
+    out: &'w mut dyn Write,
+    cap: usize
+}
+
+impl<'de> DeserializeSeed<'de> for StreamResponse<'_> {
+    type Value = bool;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for StreamResponse<'_> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an _all_docs response")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<bool, A::Error> {
+        let io = |err: std::io::Error| de::Error::custom(err);
+        let (mut offset, mut total_rows, mut truncated) = (sj::Value::Null, sj::Value::Null, false);
+        self.out.write_all(b"{\"rows\":[").map_err(io)?;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "rows" => truncated = map.next_value_seed(StreamRows { out: &mut *self.out, cap: self.cap })?,
+                "offset" => offset = map.next_value()?,
+                "total_rows" => total_rows = map.next_value()?,
+                _ => { map.next_value::<IgnoredAny>()?; }
+            }
+        }
+        write!(self.out, "],\"offset\":{},\"total_rows\":{}}}", offset, total_rows).map_err(io)?;
+        Ok(truncated)
+    }
+}
+
+struct StreamRows<'w> {
+    out: &'w mut dyn Write,
+    cap: usize
+}
+
+impl<'de> DeserializeSeed<'de> for StreamRows<'_> {
+    type Value = bool;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for StreamRows<'_> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an array of rows")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<bool, A::Error> {
+        let (mut written, mut truncated) = (0, false);
+        while let Some(row) = seq.next_element::<CDBRecord>()? {
+            if written == self.cap {
+                truncated = true;
+                continue
+            }
+            if written > 0 {
+                self.out.write_all(b",").map_err(de::Error::custom)?;
+            }
+            sj::to_writer(&mut *self.out, &row).map_err(de::Error::custom)?;
+            written += 1;
+        }
+        Ok(truncated)
+    }
+}
+
+// One page of `_all_docs`, read whole when `auto_page` loops over them
+#[derive(Deserialize, Debug)]
+struct CDBPage {
+    rows: Vec<CDBRecord>,
+    total_rows: i64
+}
+
+// Rows requested per `_all_docs` page with `auto_page=true`
+const PAGE_SIZE: usize = 1000;
+
+// Loop over pages, `fetch(limit, skip)` returning each, until a short page
+// or `cap` rows. Returns the rows, the last `total_rows` and whether every
+// row was read (`complete`), probing one row past the cap to tell. The
+// first page failing to `fetch` stops the loop with its error.
+fn collect_pages<F, E>(cap: usize, page_size: usize, mut fetch: F) -> Result<(Vec<CDBRecord>, i64, bool), E>
+where F: FnMut(usize, usize) -> Result<CDBPage, E> {
+    let (mut rows, mut total_rows) = (Vec::new(), 0);
+    while rows.len() <= cap {
+        let limit = page_size.min(cap + 1 - rows.len());
+        let page = fetch(limit, rows.len())?;
+        let short = page.rows.len() < limit;
+        total_rows = page.total_rows;
+        rows.extend(page.rows);
+        if short {
+            return Ok((rows, total_rows, true))
+        }
+    }
+    rows.truncate(cap);
+    Ok((rows, total_rows, false))
+}
+
+// Most rows a single activation returns, overridable via `MAX_ROWS`
+const MAX_ROWS: usize = 10_000;
+
+fn max_rows() -> usize {
+    env::var("MAX_ROWS").ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&value| value > 0)
+        .unwrap_or(MAX_ROWS)
+}
+
+
+// Successful outcome of `run`: a body answered as is, or one whose `data`
+// is streamed from Cloudant's response, up to `cap` rows
+enum Output {
+    Body(sj::Value),
+    Streamed { body: sj::Value, cdb_resp: Box<ureq::Response>, cap: usize }
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    match run() {
+        Ok(Output::Body(body)) => respond_ok(body),
+        Ok(Output::Streamed { body, cdb_resp, cap }) => respond_ok_streamed(body, "data", |out| {
+            let truncated = stream_rows(cdb_resp.into_reader(), out, cap)?;
+            let mut extra = sj::Map::new();
+            if truncated {
+                extra.insert("truncated".to_string(), json!(true));
+            }
+            Ok(extra)
+        }),
+        Err(err) => respond_fn_err(err)
+    }
+}
+
+fn run() -> Result<Output, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Collect the optional ordering and id range params forwarded to Cloudant
+    let mut params: Vec<(&str, String)> = Vec::new();
+    if let Some(value) = query_param(&i.__ow_query, "descending") {
+        if value != "true" && value != "false" {
+            // Boolean param with an unexpected value
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Invalid descending query param: expected true or false"))
+        }
+        params.push(("descending", value));
+    }
+    for name in &["startkey", "endkey"] {
+        if let Some(value) = query_param(&i.__ow_query, name) {
+            // Fails on a range bound that isn't a valid JSON string
+            let key = json_string_param(&value)
+                .map_err(|msg| FnError::new(ErrorCode::BadRequest, msg))?;
+            params.push((name, key));
+        }
+    }
+
+    // Rows are capped at `MAX_ROWS` whatever `limit` the client asks for.
+    // When the cap applies one extra row is requested, to tell whether
+    // rows were actually left out.
+    let cap = max_rows();
+    let limit = match query_param(&i.__ow_query, "limit").map(|value| value.parse::<usize>()) {
+        None => None,
+        Some(Ok(limit)) => Some(limit),
+        Some(Err(_)) => {
+            // Limit that isn't a row count
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Invalid limit query param: expected a non-negative integer"))
+        }
+    };
+    let capped = limit.is_none_or(|limit| limit > cap);
+    let row_limit = if capped { cap + 1 } else { limit.unwrap_or(cap) };
+
+    // `auto_page=true` hides paging: `_all_docs` is read page by page with
+    // `limit` and `skip`, up to the cap or the client's smaller `limit`
+    let auto_page = query_flag(&i.__ow_query, "auto_page");
+    if !auto_page {
+        params.push(("limit", row_limit.to_string()));
+    }
+
+    // Read the optional `keys` filter from the request body
+    let bytes = decode_optional_body(&i.__ow_body)?;
+    let keys = match bytes {
+        Some(bytes) => sj::from_slice::<ICFRequestBody>(&bytes)?.keys,
+        None => None
+    };
+
+    if auto_page && keys.is_some() {
+        // Keys already bound the rows, there is nothing to page over
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, "Invalid auto_page query param: it can't be combined with keys"))
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Query Cloudant the database
+    let uri = join_url(&config.db_url, &format!("{}/_all_docs", &config.database));
+    let with_params = |req: ureq::Request| params.iter()
+        .fold(req, |req, (name, value)| req.query(name, value));
+
+    // Every page with the same token, each one read whole
+    if auto_page {
+        let page_cap = limit.map_or(cap, |limit| limit.min(cap));
+        let (rows, total_rows, complete) = collect_pages(page_cap, PAGE_SIZE, |limit, skip| {
+            let cdb_resp = timings.db(|| cloudant_call("GET", || with_params(config.request("GET", &uri))
+                .query("limit", &limit.to_string())
+                .query("skip", &skip.to_string())
+                .set("Authorization", &authorization)
+                .call()))?;
+            read_cloudant_json::<CDBPage>(cdb_resp)
+                .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))
+        })?;
+        let mut body = json!({
+            "err": false,
+            "msg": "fetch_all execution complete!",
+            "data": {"offset": 0, "rows": rows, "total_rows": total_rows},
+            "complete": complete
+        });
+        if query_flag(&i.__ow_query, "timings") {
+            body["timings"] = timings.to_json();
+        }
+        return Ok(Output::Body(body))
+    }
+    let cdb_resp = timings.db(|| match &keys {
+        // Only the requested rows: POST the keys to `_all_docs`
+        Some(keys) => {
+            let payload = json!({"keys": keys});
+            let request = with_params(config.request("POST", &uri))
+                .set("Authorization", &authorization);
+            cloudant_call("POST", || send_json_body(request.clone(), &payload))
+        },
+        None => cloudant_call("GET", || with_params(config.request("GET", &uri))
+            .set("Authorization", &authorization)
+            .call())
+    })?;
+
+    // Build output struct around the rows streamed from Cloudant
+    let mut body = json!({
+        "err": false,
+        "msg": "fetch_all execution complete!"
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    // The probe row beyond the cap is dropped while streaming
+    let stream_cap = if capped { cap } else { row_limit };
+    Ok(Output::Streamed { body, cdb_resp: Box::new(cdb_resp), cap: stream_cap })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_until_short_page_or_cap() {
+        // 25 rows in the database, served by `skip`/`limit`
+        let fetch = |limit: usize, skip: usize| {
+            let rows = (skip..25.min(skip + limit))
+                .map(|n| sj::from_value(json!({"id": n.to_string(), "key": n.to_string(), "value": {"rev": "1-a"}})).unwrap())
+                .collect();
+            Ok::<_, String>(CDBPage { rows, total_rows: 25 })
+        };
+        let (rows, total_rows, complete) = collect_pages(100, 10, fetch).unwrap();
+        assert_eq!((rows.len(), total_rows, complete), (25, 25, true));
+        let (rows, _, complete) = collect_pages(20, 10, fetch).unwrap();
+        assert_eq!((rows.len(), complete), (20, false));
+        assert_eq!(rows[19].key, "19");
+        let (rows, _, complete) = collect_pages(25, 10, fetch).unwrap();
+        assert_eq!((rows.len(), complete), (25, true));
+
+        // A failing page stops the loop
+        let failing = |_: usize, skip: usize| if skip < 10 { fetch(10, skip) } else { Err("page failed".to_string()) };
+        assert_eq!(collect_pages(100, 10, failing).err(), Some("page failed".to_string()));
+    }
+
+    #[test]
+    fn streams_rows_up_to_cap() {
+        let cdb = br#"{"total_rows": 3, "offset": 0, "rows": [
+            {"id": "a", "key": "a", "value": {"rev": "1-a"}},
+            {"id": "b", "key": "b", "value": {"rev": "1-b"}},
+            {"key": "c", "error": "not_found"}
+        ]}"#;
+        let mut out = Vec::new();
+        assert_eq!(stream_rows(&cdb[..], &mut out, 10), Ok(false));
+        let data: sj::Value = sj::from_slice(&out).unwrap();
+        assert_eq!(data["total_rows"], 3);
+        assert_eq!(data["rows"][2], json!({"key": "c", "error": "not_found"}));
+
+        let mut out = Vec::new();
+        assert_eq!(stream_rows(&cdb[..], &mut out, 2), Ok(true));
+        let data: sj::Value = sj::from_slice(&out).unwrap();
+        assert_eq!(data["rows"].as_array().unwrap().len(), 2);
+        assert_eq!(data["offset"], 0);
+    }
+}
+
@@ -0,0 +1,139 @@
+use std::env;
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, cloudant_error, decode_body, ErrorCode, FnError, join_url, query_flag, read_cloudant_json, respond_result, server_prelude, Timings, valid_database_name};
+
+
+/* Example decoded request body:
+{
+    "databases": ["tenant-a", "tenant-b"]
+} */
+#[derive(Deserialize, Debug)]
+struct ICFRequestBody {
+    databases: Vec<String>
+}
+
+/* Example HTTP response from IBM Cloudant, for each database:
+{
+    "offset": 0,
+    "rows": [
+        {
+            "id": "exampleid",
+            "key": "exampleid",
+            "value": {
+                "rev": "1-967a00dff5e02add41819138abb3284d"
+            }
+        }
+    ],
+    "total_rows": 1
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    rows: Vec<sj::Value>,
+    total_rows: i64
+}
+
+// Most databases a single activation fans out to, overridable via
+// `MAX_DATABASES`
+const MAX_DATABASES: usize = 20;
+
+fn max_databases() -> usize {
+    env::var("MAX_DATABASES").ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&value| value > 0)
+        .unwrap_or(MAX_DATABASES)
+}
+
+// Check the requested databases: at least one, at most `max`, all valid
+// database names
+fn validate_databases(databases: &[String], max: usize) -> Result<(), String> {
+    if databases.is_empty() {
+        return Err("Missing databases: provide a non-empty array of database names".to_string())
+    }
+    if databases.len() > max {
+        return Err(format!("Too many databases: {} requested, at most {} allowed", databases.len(), max))
+    }
+    match databases.iter().find(|name| !valid_database_name(name)) {
+        Some(name) => Err(format!("Invalid databases: {} is not a valid database name", name)),
+        None => Ok(())
+    }
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, no database needed at the server level
+    let (i, config) = server_prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes
+    let request: ICFRequestBody = sj::from_slice(&bytes)?;
+    if let Err(msg) = validate_databases(&request.databases, max_databases()) {
+        // No database, too many of them, or an invalid name
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, msg))
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Query each database in turn with the same token. A failing database
+    // gets its error reported in place of its rows, the others still run.
+    let mut results = sj::Map::new();
+    for database in &request.databases {
+        let uri = join_url(&config.db_url, &format!("{}/_all_docs", database.replace('/', "%2F")));
+        let result = timings.db(|| cloudant_call("GET", || config.request("GET", &uri)
+            .set("Authorization", &authorization)
+            .call()))
+            .map_err(|err| {
+                let err = cloudant_error(err);
+                json!({"msg": err.msg, "status": err.status, "cloudant_error": err.body})
+            })
+            .and_then(|res| read_cloudant_json::<CDBResponse>(res)
+                .map_err(|msg| json!({"msg": msg})));
+        let entry = match result {
+            Ok(cdb_data) => json!(cdb_data),
+            Err(error) => json!({"error": error})
+        };
+        results.insert(database.clone(), entry);
+    }
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "fetch_multi execution complete!",
+        "results": results
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_and_validates_databases() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<String>>();
+        assert!(validate_databases(&names(&["tenant-a", "team/b"]), 2).is_ok());
+        assert!(validate_databases(&names(&[]), 2).is_err());
+        assert!(validate_databases(&names(&["a", "b", "c"]), 2).unwrap_err().starts_with("Too many databases"));
+        assert!(validate_databases(&names(&["Tenant"]), 2).is_err());
+    }
+}
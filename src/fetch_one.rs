@@ -0,0 +1,283 @@
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, Config, doc_url, ErrorCode, FnError, forward_headers, prelude, query_flag, query_param, read_cloudant_json, respond_fn_err, respond_ok_with_headers, response_headers, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (with `?conflicts=true&revs_info=true`):
+{
+    "_id": "exampleid",
+    "_rev": "2-7051cbe5c8faecd085a3fa619e6e6337",
+    "_conflicts": ["2-b91bb807b4685080c6a651115ff558f5"],
+    "_revs_info": [
+        {"rev": "2-7051cbe5c8faecd085a3fa619e6e6337", "status": "available"},
+        {"rev": "1-967a00dff5e02add41819138abb3284d", "status": "missing"}
+    ],
+    "task": "write docs",
+    "done": false
+}
+`_conflicts` is only present when requested and the document has any,
+`_revs_info` only when requested. */
+type CDBResponse = sj::Map<String, sj::Value>;
+
+// Opt-in boolean query params, off by default so normal fetches stay lean
+fn opt_in(query: &str, name: &str) -> Result<bool, FnError> {
+    match query_param(query, name).as_deref() {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(_) => Err(FnError::new(ErrorCode::BadRequest, format!("Invalid {} query param: expected true or false", name)))
+    }
+}
+
+// What to fetch, read from the query string before any IAM or Cloudant call
+#[derive(Debug, PartialEq)]
+struct Params {
+    id: String,
+    conflicts: bool,
+    revs_info: bool,
+    rev: Option<String>,
+    attachments: bool
+}
+
+fn params(query: &str) -> Result<Params, FnError> {
+    // Read the document id from the query string
+    let id = match query_param(query, "id") {
+        Some(id) if !id.is_empty() => id,
+        _ => {
+            // Document id not provided
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Missing id query param"))
+        }
+    };
+
+    // Opt-in `conflicts=true` and `revs_info=true` query params, the latter
+    // listing which revisions are still available locally
+    let conflicts = opt_in(query, "conflicts")?;
+    let revs_info = opt_in(query, "revs_info")?;
+
+    // Optional `rev` query param: fetch that revision of the document
+    // instead of the current one, e.g. for audits
+    let rev = query_param(query, "rev").filter(|rev| !rev.is_empty());
+
+    // Opt-in `attachments=true`: attachment bodies inline (base64) instead
+    // of stubs
+    let attachments = opt_in(query, "attachments")?;
+
+    Ok(Params { id, conflicts, revs_info, rev, attachments })
+}
+
+
+fn main() {
+    // The wsk function output, with the caching headers forwarded from
+    // Cloudant, or its error response, is pushed to stdout
+    match run() {
+        Ok((body, headers)) => respond_ok_with_headers(body, headers),
+        Err(err) => respond_fn_err(err)
+    }
+}
+
+fn run() -> Result<(sj::Value, sj::Map<String, sj::Value>), FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Document id and options, see `Params`
+    let params = params(&i.__ow_query)?;
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    let (mut body, headers) = fetch(&params, &config, &authorization, &mut timings)?;
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok((body, headers))
+}
+
+// Fetch the document of `params` from Cloudant: the output struct, and the
+// caching headers to forward
+fn fetch(params: &Params, config: &Config, authorization: &str, timings: &mut Timings) -> Result<(sj::Value, sj::Map<String, sj::Value>), FnError> {
+    let Params { id, conflicts, revs_info, rev, attachments } = params;
+
+    // Fetch the document from Cloudant
+    let uri = doc_url(&config.db_url, &config.database, id);
+    let mut request = config.request("GET", &uri)
+        .set("Authorization", authorization);
+    if *conflicts {
+        request = request.query("conflicts", "true");
+    }
+    if *revs_info {
+        request = request.query("revs_info", "true");
+    }
+    if let Some(rev) = rev {
+        request = request.query("rev", rev);
+    }
+    if *attachments {
+        // Cloudant answers multipart/related otherwise
+        request = request.query("attachments", "true").set("Accept", "application/json");
+    }
+    let cdb_resp = match timings.db(|| cloudant_call("GET", || request.clone().call())) {
+            Ok(res) => res,
+            Err(ureq::Error::Status(404, _)) if rev.is_some() => {
+                // Old revisions only keep their body until the database is
+                // compacted
+                // Return error message
+                return Err(FnError::new(ErrorCode::NotFound, format!(
+                    "Revision {} of document {} not found: the document does not exist or the revision was compacted away",
+                    rev.as_deref().unwrap_or_default(), id
+                )))
+            },
+            Err(err) => {
+                // Failure querying Cloudant, report its error body
+                return Err(FnError::from(err))
+            }
+        };
+
+    // Keep the caching headers (`ETag` by default) before the body is read
+    let headers = response_headers(&cdb_resp, &forward_headers());
+
+    // A matching `If-None-Match` from `forward_headers`: the client's copy
+    // is current, there is no body to return
+    if cdb_resp.status() == 304 {
+        return Ok((json!({
+            "err": false,
+            "msg": "fetch_one execution complete!",
+            "not_modified": true
+        }), headers))
+    }
+
+    // Deserialize Cloudant response
+    let mut cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct, surfacing `_conflicts` and `_revs_info` next to
+    // the document
+    let mut body = json!({
+        "err": false,
+        "msg": "fetch_one execution complete!"
+    });
+    if *conflicts {
+        let revs = cdb_data.remove("_conflicts").unwrap_or_else(|| json!([]));
+        body["conflicts"] = revs;
+    }
+    if *revs_info {
+        let revs = cdb_data.remove("_revs_info").unwrap_or_else(|| json!([]));
+        body["revs_info"] = revs;
+    }
+    body["data"] = json!(cdb_data);
+    if *attachments {
+        body["warning"] = json!("attachments=true inlines every attachment as base64, which can greatly increase the response size");
+    }
+
+    Ok((body, headers))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    fn config(server: &mockito::Server) -> Config {
+        Config {
+            iam_apikey: None,
+            username: None,
+            password: None,
+            db_url: server.url(),
+            database: "todos".to_string(),
+            headers: Vec::new()
+        }
+    }
+
+    fn fetch_query(server: &mockito::Server, query: &str) -> Result<(sj::Value, sj::Map<String, sj::Value>), FnError> {
+        fetch(&params(query)?, &config(server), "Bearer token", &mut Timings::start())
+    }
+
+    #[test]
+    fn rejects_invalid_booleans() {
+        for query in ["id=abc&conflicts=yes", "id=abc&revs_info=1", "id=abc&attachments=TRUE"] {
+            let err = params(query).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::BadRequest, "{}", query);
+        }
+        assert_eq!(params("conflicts=true").unwrap_err().code(), ErrorCode::BadRequest);
+        assert_eq!(params("id=abc&conflicts=false").unwrap(), Params {
+            id: "abc".to_string(),
+            conflicts: false,
+            revs_info: false,
+            rev: None,
+            attachments: false
+        });
+    }
+
+    #[test]
+    fn forwards_query_params() {
+        let mut server = mockito::Server::new();
+        let mock = server.mock("GET", "/todos/abc")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("conflicts".to_string(), "true".to_string()),
+                Matcher::UrlEncoded("revs_info".to_string(), "true".to_string()),
+                Matcher::UrlEncoded("rev".to_string(), "2-def".to_string())
+            ]))
+            .match_header("authorization", "Bearer token")
+            .with_header("ETag", "\"2-def\"")
+            .with_body(r#"{"_id": "abc", "_rev": "2-def", "_conflicts": ["2-abc"], "_revs_info": [{"rev": "2-def", "status": "available"}], "done": false}"#)
+            .create();
+
+        let (body, headers) = fetch_query(&server, "id=abc&conflicts=true&revs_info=true&rev=2-def").unwrap();
+        mock.assert();
+        assert_eq!(body["conflicts"], json!(["2-abc"]));
+        assert_eq!(body["revs_info"], json!([{"rev": "2-def", "status": "available"}]));
+        assert_eq!(body["data"], json!({"_id": "abc", "_rev": "2-def", "done": false}));
+        assert_eq!(headers["ETag"], "\"2-def\"");
+    }
+
+    #[test]
+    fn asks_json_with_attachments() {
+        let mut server = mockito::Server::new();
+        let mock = server.mock("GET", "/todos/abc")
+            .match_query(Matcher::UrlEncoded("attachments".to_string(), "true".to_string()))
+            .match_header("accept", "application/json")
+            .with_body(r#"{"_id": "abc", "_rev": "1-abc", "_attachments": {"notes.txt": {"content_type": "text/plain", "data": "aGk="}}}"#)
+            .create();
+
+        let (body, _) = fetch_query(&server, "id=abc&attachments=true").unwrap();
+        mock.assert();
+        assert_eq!(body["data"]["_attachments"]["notes.txt"]["data"], "aGk=");
+        assert!(body["warning"].as_str().unwrap().contains("greatly increase"), "{}", body);
+    }
+
+    #[test]
+    fn explains_missing_revision() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/todos/abc")
+            .match_query(Matcher::UrlEncoded("rev".to_string(), "1-abc".to_string()))
+            .with_status(404)
+            .with_body(r#"{"error": "not_found", "reason": "missing"}"#)
+            .create();
+
+        let err = fetch_query(&server, "id=abc&rev=1-abc").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert_eq!(err.to_string(), "Revision 1-abc of document abc not found: the document does not exist or the revision was compacted away");
+    }
+
+    #[test]
+    fn passes_not_modified_through() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/todos/abc")
+            .with_status(304)
+            .with_header("ETag", "\"1-abc\"")
+            .create();
+
+        let (body, headers) = fetch_query(&server, "id=abc").unwrap();
+        assert_eq!(body, json!({
+            "err": false,
+            "msg": "fetch_one execution complete!",
+            "not_modified": true
+        }));
+        assert_eq!(headers["ETag"], "\"1-abc\"");
+    }
+}
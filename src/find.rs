@@ -0,0 +1,132 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, decode_body, ErrorCode, FnError, join_url, prelude, query_flag, query_param, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "docs": [
+        {
+            "_id": "exampleid",
+            "_rev": "1-967a00dff5e02add41819138abb3284d",
+            "task": "write docs",
+            "done": false
+        }
+    ],
+    "bookmark": "g1AAAABweJzLYWBgYMpgSmHgKy5JLCrJTq2MT8lPzkzJBYpzWBoaWRqC5TnQlEAAAABhCAM",
+    "warning": "No matching index found, create an index to optimize query time."
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    docs: Vec<sj::Value>,
+    bookmark: Option<String>,
+    warning: Option<String>
+}
+
+// `use_index` forces the index serving the query: a design document name,
+// or a `[ddoc, index]` pair
+fn validate_use_index(use_index: &sj::Value) -> Result<(), String> {
+    let non_empty = |value: &sj::Value| value.as_str().is_some_and(|name| !name.is_empty());
+    let valid = match use_index {
+        sj::Value::String(_) => non_empty(use_index),
+        sj::Value::Array(names) => (1..=2).contains(&names.len()) && names.iter().all(non_empty),
+        _ => false
+    };
+    if !valid {
+        return Err("Invalid use_index: expected a design document name or a [ddoc, index] pair".to_string())
+    }
+    Ok(())
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes into a Mango query, e.g.
+    // {"selector": {"done": false}, "fields": ["_id", "task"], "limit": 10}
+    let query: sj::Value = sj::from_slice(&bytes)?;
+
+    // An optional `use_index` is forwarded with the query once its shape is
+    // checked. Cloudant answers 400 if the index doesn't exist or can't serve
+    // the query, and that error is reported as is.
+    if let Some(use_index) = query.get("use_index") {
+        // Index selection that is neither a name nor a pair
+        validate_use_index(use_index).map_err(|msg| FnError::new(ErrorCode::BadRequest, msg))?;
+    }
+
+    // An optional `partition` query param scopes the query to a single
+    // partition of a partitioned database
+    let partition = query_param(&i.__ow_query, "partition");
+    if let Some(p) = &partition {
+        let msg = if p.is_empty() || p.starts_with('_') || p.contains('/') {
+            Some(format!("Invalid partition key: {}", p))
+        } else if query.get("selector").is_none() {
+            Some("Missing selector: a partitioned query requires a selector".to_string())
+        } else {
+            None
+        };
+        if let Some(msg) = msg {
+            // Invalid partitioned query
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, msg))
+        }
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Query Cloudant the database (partition-scoped when requested)
+    let uri = match &partition {
+        Some(p) => join_url(&config.db_url, &format!("{}/_partition/{}/_find", &config.database, p)),
+        None => join_url(&config.db_url, &format!("{}/_find", &config.database))
+    };
+    let request = config.request("POST", &uri)
+        .set("Authorization", &authorization);
+    let cdb_resp = timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &query)))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "find execution complete!",
+        "data": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_use_index_shape() {
+        assert!(validate_use_index(&json!("by-done")).is_ok());
+        assert!(validate_use_index(&json!(["by-done", "done-idx"])).is_ok());
+        assert!(validate_use_index(&json!("")).is_err());
+        assert!(validate_use_index(&json!(["a", "b", "c"])).is_err());
+        assert!(validate_use_index(&json!({"ddoc": "by-done"})).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use std::io::Read;
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{attachment_url, cloudant_call, ErrorCode, FnError, prelude, query_flag, query_param, respond_result, Timings};
+
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Read the document id and attachment name from the query string
+    let (id, name) = match (
+        query_param(&i.__ow_query, "id"),
+        query_param(&i.__ow_query, "name")
+    ) {
+        (Some(id), Some(name)) if !id.is_empty() && !name.is_empty() => (id, name),
+        _ => {
+            // Document id or attachment name not provided
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Missing id or name query param"))
+        }
+    };
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Download the attachment from Cloudant
+    let uri = attachment_url(&config.db_url, &config.database, &id, &name);
+    let cdb_resp = match timings.db(|| cloudant_call("GET", || config.request("GET", &uri)
+        .set("Authorization", &authorization)
+        .call())) {
+            Ok(res) => res,
+            Err(ureq::Error::Status(404, _)) => {
+                // Missing document, or document without that attachment
+                // Return error message
+                return Err(FnError::new(ErrorCode::NotFound, format!("Attachment {} not found: the document {} or its attachment does not exist", name, id)))
+            },
+            Err(err) => {
+                // Failure querying Cloudant, report its error body
+                return Err(FnError::from(err))
+            }
+        };
+
+    // Read the raw attachment bytes; OpenWhisk results must be JSON, so
+    // they are base64 re-encoded for the response
+    let content_type = cdb_resp.content_type().to_string();
+    let mut data = Vec::new();
+    if let Err(err) = cdb_resp.into_reader().read_to_end(&mut data) {
+        // Failure reading the attachment bytes
+        // Return error message
+        return Err(FnError::new(ErrorCode::CloudantError, format!("Failure reading Cloudant response: {}", err)))
+    }
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "get_attachment execution complete!",
+        "content_type": content_type,
+        "data": base64::encode(&data)
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, ErrorCode, FnError, join_url, prelude, query_flag, read_cloudant_json, respond_result, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (`_security`, `{}` when nothing
+was ever set):
+{
+    "admins": {"names": ["admin"], "roles": []},
+    "members": {"names": ["reader"], "roles": ["team"]},
+    "cloudant": {"nobody": ["_reader"], "apikey-abc": ["_reader", "_writer"]}
+}
+`cloudant` holds the permissions granted through the Cloudant dashboard,
+by user or API key, next to CouchDB's own `admins` and `members`. */
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+struct CDBSecurity {
+    #[serde(default)]
+    admins: CDBRoles,
+    #[serde(default)]
+    members: CDBRoles,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cloudant: Option<BTreeMap<String, Vec<String>>>
+}
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+struct CDBRoles {
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    roles: Vec<String>
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Fetch the security document of the database
+    let uri = join_url(&config.db_url, &format!("{}/_security", &config.database));
+    let cdb_resp = timings.db(|| cloudant_call("GET", || config.request("GET", &uri)
+        .set("Authorization", &authorization)
+        .call()))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBSecurity>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "get_security execution complete!",
+        "security": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_security_document() {
+        let security: CDBSecurity = sj::from_str(r#"{
+            "admins": {"names": ["admin"], "roles": []},
+            "members": {"roles": ["team"]},
+            "cloudant": {"nobody": ["_reader"]}
+        }"#).unwrap();
+        assert_eq!(security.admins.names, vec!["admin"]);
+        assert_eq!(security.members.roles, vec!["team"]);
+        assert_eq!(security.cloudant.unwrap()["nobody"], vec!["_reader"]);
+        assert_eq!(sj::from_str::<CDBSecurity>("{}").unwrap(), CDBSecurity::default());
+    }
+}
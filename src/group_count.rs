@@ -0,0 +1,144 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, cloudant_error, ErrorCode, FnError, join_url, path_segment, prelude, query_flag, query_param, read_cloudant_json, respond_result, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (reduce view, `group=true`):
+{
+    "rows": [
+        {"key": "done", "value": 12},
+        {"key": "todo", "value": 3}
+    ]
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    rows: Vec<CDBRow>
+}
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBRow {
+    key: sj::Value,
+    value: sj::Value
+}
+
+// Turn the grouped rows into a `{key: count}` map. String keys are used
+// as-is, other keys (e.g. arrays with `group_level`) JSON-encoded.
+fn counts(rows: Vec<CDBRow>) -> sj::Map<String, sj::Value> {
+    rows.into_iter()
+        .map(|row| {
+            let key = match row.key {
+                sj::Value::String(key) => key,
+                key => key.to_string()
+            };
+            (key, row.value)
+        })
+        .collect()
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Read the design document and view names from the query string
+    let (design, view) = match (
+        query_param(&i.__ow_query, "design"),
+        query_param(&i.__ow_query, "view")
+    ) {
+        (Some(d), Some(v)) if !d.is_empty() && !v.is_empty() => (d, v),
+        _ => {
+            // Design document or view name not provided
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Missing design or view query param"))
+        }
+    };
+
+    // Both names stay single path segments of the database, see
+    // `path_segment`
+    let segment = |name: &str| path_segment(name).map_err(|msg| FnError::new(ErrorCode::BadRequest, msg));
+    let path = format!("{}/_design/{}/_view/{}", &config.database, segment(&design)?, segment(&view)?);
+
+    // Optional `group_level`, to group array keys by their first items
+    let group_level = match query_param(&i.__ow_query, "group_level").map(|value| value.parse::<u32>()) {
+        None => None,
+        Some(Ok(level)) if level > 0 => Some(level),
+        Some(_) => {
+            // Group level that isn't a positive integer
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Invalid group_level query param: expected a positive integer"))
+        }
+    };
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Query the Cloudant view, reduced and grouped server-side
+    let uri = join_url(&config.db_url, &path);
+    let cdb_resp = match timings.db(|| cloudant_call("GET", || {
+        let request = config.request("GET", &uri)
+            .query("reduce", "true")
+            .set("Authorization", &authorization);
+        match group_level {
+            Some(level) => request.query("group_level", &level.to_string()),
+            None => request.query("group", "true")
+        }.call()
+    })) {
+            Ok(res) => res,
+            Err(ureq::Error::Status(400, res)) => {
+                // Most likely a map-only view: say so next to Cloudant's reason
+                let err = cloudant_error(ureq::Error::Status(400, res));
+                return Err(FnError::new(ErrorCode::BadRequest, format!(
+                    "View {}/{} can't be grouped, it needs a reduce function (e.g. _count): {}", design, view, err.msg
+                )))
+            },
+            Err(err) => {
+                // Failure querying Cloudant, report its error body
+                return Err(FnError::from(err))
+            }
+        };
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "group_count execution complete!",
+        "counts": counts(cdb_data.rows)
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_keys_to_counts() {
+        let rows: Vec<CDBRow> = sj::from_value(json!([
+            {"key": "done", "value": 12},
+            {"key": ["todo", 2021], "value": 3},
+            {"key": null, "value": 1}
+        ])).unwrap();
+        let counts = counts(rows);
+        assert_eq!(counts["done"], 12);
+        assert_eq!(counts[r#"["todo",2021]"#], 3);
+        assert_eq!(counts["null"], 1);
+    }
+}
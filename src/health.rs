@@ -0,0 +1,74 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{ErrorCode, FnError, query_flag, read_cloudant_json, respond_result, server_prelude, Timings, traced};
+
+
+/* Example HTTP response from IBM Cloudant (server root):
+{
+    "couchdb": "Welcome",
+    "version": "3.2.1",
+    "vendor": {
+        "name": "IBM Cloudant",
+        "version": "8521",
+        "variant": "paas"
+    },
+    "features": ["access-ready", "partitioned", "pluggable-storage-engines", "scheduler"]
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    version: String
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, no database needed at the server level
+    let (i, config) = server_prelude()?;
+
+    // Check IAM: request a token from IBM Cloud (skipped with AUTH_MODE=basic).
+    // Fails when IAM is unreachable or rejects the credentials.
+    let authorization = timings.iam(|| config.authorization())
+        .map_err(|msg| FnError::detailed("503 Service Unavailable", ErrorCode::IamFailure, msg, json!({
+            "iam": "failed",
+            "cloudant": "skipped"
+        })))?;
+
+    // Check Cloudant: its server root answers with the version. Probes
+    // want a prompt answer, so the request is not retried.
+    let checked = timings.db(|| traced("cloudant", "GET", || config.request("GET", &config.db_url)
+        .set("Authorization", &authorization)
+        .call()))
+        .map_err(|err| format!("Failure querying Cloudant: {}", err))
+        .and_then(read_cloudant_json::<CDBResponse>);
+    let cdb_data = checked.map_err(|msg| {
+        // Cloudant unreachable, or not answering as expected
+        FnError::detailed("503 Service Unavailable", ErrorCode::CloudantError, msg, json!({
+            "iam": "ok",
+            "cloudant": "failed"
+        }))
+    })?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "health execution complete!",
+        "iam": "ok",
+        "cloudant": "ok",
+        "version": cdb_data.version
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
@@ -1,63 +1,21 @@
-use std::{env, process};
+use std::{env, time::SystemTime};
+use ring::digest;
 use serde::{Serialize, Deserialize};
 use serde_json::{self as sj};
 use ureq::json as json;
-use base64::decode;
+use shared::{cloudant_call, cloudant_create, content_type, current_rev, decode_body, doc_url, ErrorCode, FieldError, FnError, form_to_json, join_url, prelude, query_flag, read_cloudant_json, respond_result, rfc3339, send_json_body, Timings, write_quorum};
 
 
+// Inline `_attachments` (`{"name": {"content_type": ..., "data": <base64>}}`)
+// are passed to Cloudant unchanged. Their base64 data is part of the request
+// body, so it counts towards `MAX_BODY_BYTES` like the rest of the document.
 #[derive(Deserialize, Serialize, Debug)]
 struct ICFRequestBody {
     _id: Option<String>,
     task: String,
-    done: bool 
-}
-
-/* Example Raw HTTP payload from IBM Cloud Functions:
-{
-    "<custom_params>": <value>,
-    ...
-    "__ow_method": "post",
-    "__ow_query": "name=Jane",
-    "__ow_body": "eyJuYW1lIjoiSmFuZSJ9",
-    "__ow_headers": {
-    "accept": "*\/\*",
-    "connection": "close",
-    "content-length": "15",
-    "content-type": "application/json",
-    "host": "172.17.0.1",
-    "user-agent": "curl/7.43.0"
-    },
-    "__ow_path": ""
-} */
-#[derive(Deserialize, Serialize)]
-struct ICFRawInput {
-    iam_apikey: String,
-    db_url: String,
-    database: String,
-    __ow_body: String,
-    __ow_headers: sj::Value,
-    __ow_method: String,
-    __ow_path: String,
-    __ow_query: String
-}
-
-/* Example HTTP response from IBM Cloud IAM:
-{
-    "access_token": "<omitted>",
-    "refresh_token": "not_supported",
-    "token_type": "Bearer",
-    "expires_in": 3600,
-    "expiration": 1616239535,
-    "scope": "ibm openid"
-} */
-#[derive(Deserialize, Serialize)]
-struct IAMResponse {
-    access_token: String,
-    refresh_token: String,
-    token_type: String,
-    expires_in: i32,
-    expiration: i32,
-    scope: String
+    done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    _attachments: Option<sj::Value>
 }
 
 /* Example HTTP response from IBM Cloudant:
@@ -85,186 +43,436 @@ struct CDBResponse {
     rev: String
 }
 
+/* Example HTTP response from IBM Cloudant `_bulk_docs`, one entry per document:
+[
+    {"ok": true, "id": "exampleid", "rev": "1-967a00dff5e02add41819138abb3284d"},
+    {"id": "otherid", "error": "conflict", "reason": "Document update conflict."}
+] */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBBulkResult {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>
+}
+
+// `id` and `rev` are repeated at the top level so callers can chain an
+// update without digging into `inserted_record`. `results` has the same
+// shape as for a batch of documents.
+fn success_body(cdb_data: &CDBResponse) -> sj::Value {
+    json!({
+        "err": false,
+        "msg": "insert execution complete!",
+        "id": cdb_data.id,
+        "rev": cdb_data.rev,
+        "inserted_record": cdb_data,
+        "results": [cdb_data]
+    })
+}
+
+// Response to a retried insert: the `Idempotency-Key` document already
+// exists, so the stored document is returned instead of a new one, in the
+// same shape as the first insert's response
+fn replay_body(existing: &sj::Value) -> sj::Value {
+    let stored = CDBResponse {
+        id: existing["_id"].as_str().unwrap_or_default().to_string(),
+        ok: true,
+        rev: existing["_rev"].as_str().unwrap_or_default().to_string()
+    };
+    let mut body = success_body(&stored);
+    body["replayed"] = json!(true);
+    body["existing_record"] = existing.clone();
+    body
+}
+
+// Response to a batch insert: one `_bulk_docs` entry per document, in order
+fn bulk_success_body(results: &[CDBBulkResult]) -> sj::Value {
+    json!({
+        "err": false,
+        "msg": "insert execution complete!",
+        "results": results
+    })
+}
+
+// Default schema check of a to-do item: an optional string `_id`, an
+// actual `task` and a boolean `done`. Every failing field is reported, not
+// just the first; kept apart from deserialization so it can go away once
+// the schema becomes generic.
+fn validate_task(document: &sj::Value) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    match document.get("_id") {
+        None | Some(sj::Value::Null) | Some(sj::Value::String(_)) => (),
+        Some(_) => errors.push(FieldError::new("_id", "must be a string"))
+    }
+    match document.get("task") {
+        Some(sj::Value::String(task)) if task.trim().is_empty() => errors.push(FieldError::new("task", "must not be empty")),
+        Some(sj::Value::String(_)) => (),
+        Some(_) => errors.push(FieldError::new("task", "must be a string")),
+        None => errors.push(FieldError::new("task", "is required"))
+    }
+    match document.get("done") {
+        Some(sj::Value::Bool(_)) => (),
+        Some(_) => errors.push(FieldError::new("done", "must be a boolean")),
+        None => errors.push(FieldError::new("done", "is required"))
+    }
+    errors
+}
+
+// Validate every document, prefixing the fields of a batch with the
+// document's index (`[1].task`)
+fn validate_documents(batch: bool, items: &[sj::Value]) -> Vec<FieldError> {
+    items.iter().enumerate()
+        .flat_map(|(index, item)| validate_task(item).into_iter().map(move |err| match batch {
+            true => FieldError::new(format!("[{}].{}", index, err.field), err.message),
+            false => err
+        }))
+        .collect()
+}
+
+// Split the parsed body into the documents to insert: a JSON array is a
+// batch written through `_bulk_docs`, anything else a single document.
+// Every document must be a JSON object, scalars can't be stored as such.
+fn split_documents(parsed: sj::Value) -> Result<(bool, Vec<sj::Value>), String> {
+    let (batch, items) = match parsed {
+        sj::Value::Array(items) => (true, items),
+        item => (false, vec![item])
+    };
+    match items.iter().position(|item| !item.is_object()) {
+        Some(_) if !batch => Err("document must be a JSON object".to_string()),
+        Some(index) => Err(format!("document {} must be a JSON object", index)),
+        None => Ok((batch, items))
+    }
+}
+
+// Content-addressable `_id`: the SHA-256 (hex) of the JSON array of the
+// document's `id_fields` values, so inserting the same content twice
+// targets the same document instead of creating a duplicate
+fn content_id(document: &sj::Value, id_fields: &[String]) -> Result<String, String> {
+    let values = id_fields.iter()
+        .map(|name| document.get(name).cloned()
+            .ok_or_else(|| format!("Failure computing _id: document has no {} field", name)))
+        .collect::<Result<Vec<sj::Value>, String>>()?;
+    let hash = digest::digest(&digest::SHA256, &sj::to_vec(&values).unwrap());
+    Ok(hash.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Fields added to every inserted document: the static ones of the
+// `DEFAULT_FIELDS` env var (a JSON object, e.g. `{"source": "functions"}`)
+// and a server-side `created_at` RFC 3339 timestamp
+fn default_fields(created_at: &str) -> Result<sj::Map<String, sj::Value>, String> {
+    let mut fields = match env::var("DEFAULT_FIELDS") {
+        Ok(raw) => match sj::from_str::<sj::Value>(&raw) {
+            Ok(sj::Value::Object(fields)) => fields,
+            _ => return Err("Invalid DEFAULT_FIELDS: expected a JSON object".to_string())
+        },
+        Err(_) => sj::Map::new()
+    };
+    fields.insert("created_at".to_string(), json!(created_at));
+    Ok(fields)
+}
+
+// Merge `defaults` into `document`, values supplied by the client win
+fn with_defaults(mut document: sj::Value, defaults: &sj::Map<String, sj::Value>) -> sj::Value {
+    if let Some(fields) = document.as_object_mut() {
+        for (name, value) in defaults {
+            fields.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+    }
+    document
+}
+
 
 fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
     
-    // Read input arguments as a vector of Strings
-    let args: Vec<String> = env::args().collect();
-    println!("{:?}", &args);
-
-    // Use serde_json to deserialize a &str into a Payload struct
-    // NOTE: The `args[0]` element is traditionally the path of
-    // the executable, but it can be set to arbitrary text, and
-    // may not even exist. This means this property should not be 
-    // relied upon for security purposes.
-    let i: ICFRawInput = match sj::from_str(&args[1]) {
-        Ok(res) => res,
-        Err(err) => {
-            // Failed to parse input into expected Rust struct
-            // Return error message
-            let o = json!({
-                "statusCode": "200 OK",
-                "body": {
-                    "err": true,
-                    "msg": format!("Failure parsing raw HTTP request: {}", err)
-                }
-            });
-            // The serverless function output is pushed to stdout
-            println!("{}", sj::to_string(&o).unwrap());
-            // The process is killed through the OS exitcode
-            process::exit(exitcode::OK)
-        }
-    };
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
 
     // Decode input request body from base64
-    let bytes = match decode(i.__ow_body) {
-        Ok(res) => res,
-        Err(err) => {
-            // Failed to decode base64 body
-            let o = json!({
-                "statusCode": "200 OK",
-                "body": {
-                    "err": true,
-                    "msg": format!("Failure decoding base64 body: {}", err)
-                }
-            });
-            // The serverless function output is pushed to stdout
-            println!("{}", sj::to_string(&o).unwrap());
-            // The process is killed through the OS exitcode
-            process::exit(exitcode::OK)
-        }
-    };
+    let bytes = decode_body(&i.__ow_body)?;
 
-    // Deserialize decoded bytes
-    let document: ICFRequestBody = match sj::from_slice(&bytes) {
-        Ok(res) => res,
-        Err(err) => {
-            // Failed to deserialize decoded bytes
-            let o = json!({
-                "statusCode": "200 OK",
-                "body": {
-                    "err": true,
-                    "msg": format!("Failure deserializing decoded bytes: {}", err)
-                }
-            });
-            // The serverless function output is pushed to stdout
-            println!("{}", sj::to_string(&o).unwrap());
-            // The process is killed through the OS exitcode
-            process::exit(exitcode::OK)
+    // Deserialize decoded bytes according to the request content type:
+    // JSON documents, or HTML form posts (`task=write+docs&done=false`)
+    let parsed = match content_type(&i.__ow_headers).as_str() {
+        "application/json" => sj::from_slice::<sj::Value>(&bytes),
+        "application/x-www-form-urlencoded" => Ok(form_to_json(&bytes)),
+        other => {
+            // Content type we don't know how to read
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, format!("Unsupported content type: {}", other)))
         }
     };
 
-    // Request IAM token from IBM Cloud
-    /* Reference request:
-        curl -X POST \
-            "https://iam.cloud.ibm.com/identity/token" \
-            --header 'Content-Type: application/x-www-form-urlencoded' \
-            --header 'Accept: application/json' \
-            --data-urlencode 'grant_type=urn:ibm:params:oauth:grant-type:apikey' \
-            --data-urlencode 'apikey={api_key}'
-    */
-    let iam_resp = match ureq::post("https://iam.cloud.ibm.com/identity/token")
-        .set("Content-Type", "application/x-www-form-urlencoded")
-        .set("Accept", "application/json")
-        .send_form(&[
-            ("apikey", &i.iam_apikey),
-            ("grant_type", "urn:ibm:params:oauth:grant-type:apikey")
-        ]) {
-            Ok(iam_resp) => iam_resp,
-            Err(_) => {
-                // Failure requesting IAM token
+
+    // A JSON array is a batch of documents, written through `_bulk_docs`.
+    // A scalar body, or a batch holding one, is refused.
+    let (batch, items) = split_documents(parsed?)
+        .map_err(|msg| FnError::new(ErrorCode::BadRequest, msg))?;
+    if items.is_empty() {
+        // Nothing to insert
+        // Return error message
+        return Err(FnError::new(ErrorCode::ValidationError, "Failure validating documents: expected at least one document"))
+    }
+    let errors = validate_documents(batch, &items);
+    if !errors.is_empty() {
+        // Documents failing the default schema, every bad field listed
+        // Return error message
+        return Err(FnError::validation(format!("Failure validating documents: {} invalid fields", errors.len()), errors))
+    }
+    let documents: Vec<ICFRequestBody> = items.into_iter().map(sj::from_value).collect::<Result<_, _>>()?;
+
+    // Optional `id_fields` input: derive missing ids from those fields,
+    // otherwise Cloudant generates them
+    let id_fields: Option<Vec<String>> = i.params.get("id_fields").cloned().map(sj::from_value).transpose()
+        .map_err(|err| FnError::new(ErrorCode::BadRequest, format!("Invalid id_fields: {}", err)))?
+        .filter(|fields: &Vec<String>| !fields.is_empty());
+    let mut documents: Vec<sj::Value> = documents.iter().map(|document| json!(document)).collect();
+
+    // Optional `Idempotency-Key` header: it becomes the `_id`, so a retried
+    // invocation hits a 409 and gets the stored document back instead of
+    // creating a duplicate. The key is trusted as is: a key reused for
+    // genuinely different content also returns the stored document,
+    // unchanged, flagged `replayed` - clients should use unique keys (UUIDs).
+    let idempotency_key = i.__ow_headers.get("idempotency-key")
+        .and_then(|key| key.as_str())
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty());
+    if let Some(key) = &idempotency_key {
+        if batch {
+            // One key can't name a batch of documents
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Idempotency-Key is only supported when inserting a single document"))
+        }
+        match documents[0]["_id"].as_str() {
+            Some(id) if id != key => {
+                // Two different ids for the same document
                 // Return error message
-                let o = json!({
-                    "statusCode": "200 OK",
-                    "body": {
-                        "err": true,
-                        "msg": format!("Failure requesting IAM token")
-                    }
-                });
-                // The serverless function output is pushed to stdout
-                println!("{}", sj::to_string(&o).unwrap());
-                // The process is killed through the OS exitcode
-                process::exit(exitcode::OK)
+                return Err(FnError::new(ErrorCode::BadRequest, "Idempotency-Key conflicts with the document _id"))
             }
-        };
+            _ => documents[0]["_id"] = json!(key)
+        }
+    }
 
-    // Deserialize IAM response
-    let iam_token = match iam_resp.into_json::<IAMResponse>() {
-        Ok(iam_data) => iam_data.access_token,
-        Err(err) => {
-            // Failure deserializing IAM response
-            // Return error message
-            let o = json!({
-                "statusCode": "200 OK",
-                "body": {
-                    "err": true,
-                    "msg": format!("Failure deserializing IAM response: {}", err)
-                }
-            });
-            // The serverless function output is pushed to stdout
-            println!("{}", sj::to_string(&o).unwrap());
-            // The process is killed through the OS exitcode
-            process::exit(exitcode::OK)
+    if let Some(id_fields) = &id_fields {
+        for document in documents.iter_mut().filter(|document| document["_id"].is_null()) {
+            // Fails when a selected field is missing from the document
+            let id = content_id(document, id_fields)
+                .map_err(|msg| FnError::new(ErrorCode::ValidationError, msg))?;
+            document["_id"] = json!(id);
         }
-    };
+    }
+
+    // Stamp the documents with the configured default fields, failing on
+    // a misconfigured DEFAULT_FIELDS
+    let defaults = default_fields(&rfc3339(SystemTime::now()))
+        .map_err(|msg| FnError::new(ErrorCode::ConfigError, msg))?;
+    let documents: Vec<sj::Value> = documents.into_iter()
+        .map(|document| with_defaults(document, &defaults))
+        .collect();
+
+    // Optional `w` write quorum, see `write_quorum`
+    let w = write_quorum(&i.__ow_query)
+        .map_err(|msg| FnError::new(ErrorCode::BadRequest, msg))?;
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // `dry_run=true`: the request parsed and IAM auth works, stop before
+    // writing anything to Cloudant
+    if query_flag(&i.__ow_query, "dry_run") {
+        return Ok(json!({
+            "err": false,
+            "msg": "insert dry run complete!",
+            "dry_run": true,
+            "valid": true
+        }))
+    }
 
-    // Query Cloudant the database
-    let uri = format!("{}/{}", &i.db_url, &i.database);
-    let bearer = format!("Bearer {}", &iam_token);
-    let cdb_resp = match ureq::post(&uri)
-        .set("Authorization", &bearer)
-        .set("Accept", "application/json")
-        .send_json(json!(document)) {
+    // Query Cloudant the database. A single document with an `_id` is PUT:
+    // without `_rev` it is create-only, Cloudant refuses it if the id is
+    // taken, with one it updates that revision.
+    let (method, uri, payload) = if batch {
+        ("POST", join_url(&config.db_url, &format!("{}/_bulk_docs", &config.database)), json!({"docs": documents}))
+    } else if let Some(id) = documents[0]["_id"].as_str() {
+        ("PUT", doc_url(&config.db_url, &config.database, id), documents[0].clone())
+    } else {
+        ("POST", join_url(&config.db_url, &config.database), documents[0].clone())
+    };
+    let mut request = config.request(method, &uri)
+        .set("Authorization", &authorization);
+    if let Some(w) = w {
+        request = request.query("w", &w.to_string());
+    }
+    // A create-only PUT isn't retried once Cloudant may have applied it, see
+    // `cloudant_create`
+    let create_only = method == "PUT" && payload.get("_rev").is_none();
+    let send = || send_json_body(request.clone(), &payload);
+    let cdb_resp = match timings.db(|| if create_only { cloudant_create(send) } else { cloudant_call(method, send) }) {
             Ok(res) => res,
-            Err(err) => {
-                // Failure querying Cloudant
+            Err(ureq::Error::Status(409, _)) if idempotency_key.is_some() => {
+                // Retried insert: answer with the document stored by the
+                // first invocation
+                let uri = doc_url(&config.db_url, &config.database, idempotency_key.as_deref().unwrap());
+                let existing = timings.db(|| cloudant_call("GET", || config.request("GET", &uri)
+                    .set("Authorization", &authorization)
+                    .call()))?;
+                let existing = read_cloudant_json::<sj::Value>(existing)
+                    .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+                let mut body = replay_body(&existing);
+                if query_flag(&i.__ow_query, "timings") {
+                    body["timings"] = timings.to_json();
+                }
+                return Ok(body)
+            },
+            Err(err @ ureq::Error::Status(409, _)) if method == "PUT" => {
+                // Hand out the document's current rev, so the client can
+                // retry without fetching it
+                let rev = current_rev(config.request("HEAD", &uri).set("Authorization", &authorization));
+                if !create_only {
+                    // Update carrying a stale `_rev`
+                    return Err(FnError::conflict(err, rev))
+                }
+                // Create-only insert of an id that is already taken
                 // Return error message
-                let o = json!({
-                    "statusCode": "200 OK",
-                    "body": {
-                        "err": true,
-                        "msg": format!("Failure querying Cloudant: {}", err)
-                    }
-                });
-                // The serverless function output is pushed to stdout
-                println!("{}", sj::to_string(&o).unwrap());
-                // The process is killed through the OS exitcode
-                process::exit(exitcode::OK)
+                return Err(FnError::detailed("200 OK", ErrorCode::AlreadyExists, format!(
+                    "Failure inserting document: document already exists: {}", payload["_id"].as_str().unwrap_or_default()
+                ), json!({"current_rev": rev})))
+            },
+            Err(err) => {
+                // Failure querying Cloudant, report its error body
+                return Err(FnError::from(err))
             }
         };
 
-    // Deserialize Cloudant response
-    let cdb_data = match cdb_resp.into_json::<CDBResponse>() {
-        Ok(res) => res,
-        Err(err) => {
-            // Failure deserializing IAM response
-            // Return error message
-            let o = json!({
-                "statusCode": "200 OK",
-                "body": {
-                    "err": true,
-                    "msg": format!("Failure deserializing IAM response: {}", err)
-                }
-            });
-            // The serverless function output is pushed to stdout
-            println!("{}", sj::to_string(&o).unwrap());
-            // The process is killed through the OS exitcode
-            process::exit(exitcode::OK)
-        }
+    // Deserialize Cloudant response and build output struct
+    let parsed = if batch {
+        read_cloudant_json::<Vec<CDBBulkResult>>(cdb_resp).map(|res| bulk_success_body(&res))
+    } else {
+        read_cloudant_json::<CDBResponse>(cdb_resp).map(|res| success_body(&res))
     };
+    let mut body = parsed.map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
 
-    // Build output struct
-    let o = json!({
-        "statusCode": "200 OK",
-        "body": json!({
-            "err": false,
-            "msg": "insert execution complete!",
-            "inserted_record": cdb_data
-        })
-    });
-
-    // The wsk function output is pushed to stdout
-    println!("{}", sj::to_string(&o).unwrap());
-    // The process is killed through the OS exitcode
-    process::exit(exitcode::OK)
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposes_id_and_rev_at_top_level() {
+        let cdb_data = CDBResponse {
+            id: "exampleid".to_string(),
+            ok: true,
+            rev: "1-967a00dff5e02add41819138abb3284d".to_string()
+        };
+        let body = success_body(&cdb_data);
+        assert_eq!(body["id"], body["inserted_record"]["id"]);
+        assert_eq!(body["rev"], body["inserted_record"]["rev"]);
+        assert_eq!(body["rev"], "1-967a00dff5e02add41819138abb3284d");
+        assert_eq!(body["results"][0], body["inserted_record"]);
+    }
+
+    #[test]
+    fn replays_existing_document() {
+        let existing = json!({"_id": "key-1", "_rev": "1-967a00dff5e02add41819138abb3284d", "task": "write docs"});
+        let body = replay_body(&existing);
+        assert_eq!(body["id"], "key-1");
+        assert_eq!(body["rev"], "1-967a00dff5e02add41819138abb3284d");
+        assert_eq!(body["replayed"], true);
+        assert_eq!(body["existing_record"], existing);
+        assert_eq!(body["results"], json!([{"id": "key-1", "ok": true, "rev": "1-967a00dff5e02add41819138abb3284d"}]));
+        assert_eq!(body["inserted_record"], body["results"][0]);
+
+        let first = success_body(&sj::from_value(body["inserted_record"].clone()).unwrap());
+        assert!(first.as_object().unwrap().keys().all(|field| body.get(field) == first.get(field)));
+    }
+
+    #[test]
+    fn lists_batch_results() {
+        let results: Vec<CDBBulkResult> = sj::from_value(json!([
+            {"ok": true, "id": "exampleid", "rev": "1-967a00dff5e02add41819138abb3284d"},
+            {"id": "otherid", "error": "conflict", "reason": "Document update conflict."}
+        ])).unwrap();
+        let body = bulk_success_body(&results);
+        assert_eq!(body["results"].as_array().unwrap().len(), 2);
+        assert_eq!(body["results"][1]["error"], "conflict");
+        assert!(body["results"][1].get("rev").is_none());
+    }
+
+    #[test]
+    fn client_fields_win_over_defaults() {
+        let defaults = default_fields("2021-03-20T11:25:35Z").unwrap();
+        let document = with_defaults(json!({"task": "write docs", "created_at": "yesterday"}), &defaults);
+        assert_eq!(document["created_at"], "yesterday");
+        let document = with_defaults(json!({"task": "write docs"}), &defaults);
+        assert_eq!(document["created_at"], "2021-03-20T11:25:35Z");
+    }
+
+    #[test]
+    fn rejects_blank_tasks() {
+        let task = |task: &str| json!({"task": task, "done": false});
+        assert!(validate_task(&task("write docs")).is_empty());
+        assert_eq!(validate_task(&task("")), vec![FieldError::new("task", "must not be empty")]);
+        assert_eq!(validate_task(&task(" \t\n")).len(), 1);
+    }
+
+    #[test]
+    fn collects_every_invalid_field() {
+        let errors = validate_task(&json!({"_id": 1, "done": "no"}));
+        let fields: Vec<&str> = errors.iter().map(|err| err.field.as_str()).collect();
+        assert_eq!(fields, vec!["_id", "task", "done"]);
+        let errors = validate_documents(true, &[json!({"task": "a", "done": false}), json!({"task": "", "done": false})]);
+        assert_eq!(errors, vec![FieldError::new("[1].task", "must not be empty")]);
+    }
+
+    #[test]
+    fn accepts_object_documents_only() {
+        let (batch, items) = split_documents(json!({"task": "write docs", "done": false})).unwrap();
+        assert!(!batch);
+        assert_eq!(items.len(), 1);
+        let (batch, items) = split_documents(json!([{"task": "a", "done": false}, {"task": "b", "done": true}])).unwrap();
+        assert!(batch);
+        assert_eq!(items.len(), 2);
+        assert_eq!(split_documents(json!("write docs")), Err("document must be a JSON object".to_string()));
+        assert_eq!(split_documents(json!(42)), Err("document must be a JSON object".to_string()));
+        assert_eq!(split_documents(json!([{"task": "a", "done": false}, 42])), Err("document 1 must be a JSON object".to_string()));
+    }
+
+    #[test]
+    fn passes_inline_attachments_through() {
+        let attachments = json!({"notes.txt": {"content_type": "text/plain", "data": "aGVsbG8gd29ybGQ="}});
+        let item = json!({"task": "write docs", "done": false, "_attachments": attachments});
+        assert!(validate_task(&item).is_empty());
+        let document: ICFRequestBody = sj::from_value(item).unwrap();
+        assert_eq!(json!(document)["_attachments"], attachments);
+
+        let document: ICFRequestBody = sj::from_value(json!({"task": "write docs", "done": false})).unwrap();
+        assert!(json!(document).get("_attachments").is_none());
+    }
+
+    #[test]
+    fn derives_stable_ids_from_fields() {
+        let fields = vec!["task".to_string(), "done".to_string()];
+        let id = content_id(&json!({"task": "write docs", "done": false}), &fields).unwrap();
+        assert_eq!(id.len(), 64);
+        assert_eq!(content_id(&json!({"done": false, "task": "write docs", "_id": null}), &fields), Ok(id.clone()));
+        assert_ne!(content_id(&json!({"task": "write docs", "done": true}), &fields), Ok(id));
+        assert!(content_id(&json!({"task": "write docs"}), &fields).is_err());
+    }
 }
@@ -0,0 +1,73 @@
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, ErrorCode, FnError, join_url, json_string_param, query_flag, query_param, read_cloudant_json, respond_result, server_prelude, Timings};
+
+
+/* Example HTTP response from IBM Cloudant:
+["_replicator", "todos", "todos-backup"] */
+type CDBResponse = Vec<String>;
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, no database needed at the server level
+    let (i, config) = server_prelude()?;
+
+    // Collect the optional range params forwarded to Cloudant
+    let mut params: Vec<(&str, String)> = Vec::new();
+    for name in &["startkey", "endkey"] {
+        if let Some(value) = query_param(&i.__ow_query, name) {
+            match json_string_param(&value) {
+                Ok(key) => params.push((name, key)),
+                Err(msg) => {
+                    // Range bound that isn't a valid JSON string
+                    // Return error message
+                    return Err(FnError::new(ErrorCode::BadRequest, msg))
+                }
+            }
+        }
+    }
+    if let Some(limit) = query_param(&i.__ow_query, "limit") {
+        if limit.parse::<u32>().is_err() {
+            // Limit that isn't a row count
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Invalid limit query param: expected a non-negative integer"))
+        }
+        params.push(("limit", limit));
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // List the databases of the Cloudant instance
+    let uri = join_url(&config.db_url, "_all_dbs");
+    let cdb_resp = timings.db(|| cloudant_call("GET", || params.iter()
+        .fold(config.request("GET", &uri), |req, (name, value)| req.query(name, value))
+        .set("Authorization", &authorization)
+        .call()))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "list_dbs execution complete!",
+        "databases": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
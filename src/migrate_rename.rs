@@ -0,0 +1,201 @@
+use serde::Deserialize;
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, ErrorCode, FnError, join_url, prelude, query_flag, query_param, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+// Documents read and rewritten per `_all_docs` / `_bulk_docs` round
+const PAGE_SIZE: usize = 200;
+
+/* Example HTTP response from IBM Cloudant (`_all_docs?include_docs=true`):
+{
+    "total_rows": 1,
+    "offset": 0,
+    "rows": [
+        {
+            "id": "exampleid",
+            "key": "exampleid",
+            "value": {"rev": "1-967a00dff5e02add41819138abb3284d"},
+            "doc": {"_id": "exampleid", "_rev": "1-967a00dff5e02add41819138abb3284d", "task": "write docs"}
+        }
+    ]
+} */
+#[derive(Deserialize, Debug)]
+struct CDBResponse {
+    rows: Vec<CDBRow>
+}
+#[derive(Deserialize, Debug)]
+struct CDBRow {
+    id: String,
+    doc: sj::Map<String, sj::Value>
+}
+
+/* Example HTTP response from IBM Cloudant (`_bulk_docs`):
+[
+    {"id": "exampleid", "ok": true, "rev": "2-7051cbe5c8faecd085a3fa619e6e6337"},
+    {"id": "otherid", "error": "conflict", "reason": "Document update conflict."}
+] */
+#[derive(Deserialize, Debug)]
+struct CDBBulkResult {
+    ok: Option<bool>
+}
+
+#[derive(Debug, PartialEq)]
+enum Rename {
+    Renamed,
+    // No `from` field, nothing to do
+    Absent,
+    // Both fields present: left alone rather than overwriting `to`
+    Collision
+}
+
+// Move the `from` field of `doc` to `to`. `_id` and `_rev` are untouched,
+// so writing the document back updates the revision that was read.
+fn rename_field(doc: &mut sj::Map<String, sj::Value>, from: &str, to: &str) -> Rename {
+    if !doc.contains_key(from) {
+        return Rename::Absent
+    }
+    if doc.contains_key(to) {
+        return Rename::Collision
+    }
+    let value = doc.remove(from).unwrap();
+    doc.insert(to.to_string(), value);
+    Rename::Renamed
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Migrations rewrite every document: they need `confirm=true`
+    if !query_flag(&i.__ow_query, "confirm") {
+        // Missing safety guard
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, "Refusing to migrate documents: requires confirm=true"))
+    }
+
+    // Read the field names from the query string
+    let (from, to) = match (query_param(&i.__ow_query, "from"), query_param(&i.__ow_query, "to")) {
+        (Some(from), Some(to)) if !from.is_empty() && !to.is_empty() => (from, to),
+        _ => {
+            // Field names not provided
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Missing from or to query param"))
+        }
+    };
+    if from == to || from.starts_with('_') || to.starts_with('_') {
+        // Same field twice, or a field reserved by Cloudant
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, "Invalid from or to query param: expected two different fields not starting with _"))
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Page through every document, one extra row telling where the next page
+    // starts, and write each page's renamed documents in one `_bulk_docs`.
+    // A failing request stops the migration, reported with the counts of
+    // the pages done before it.
+    let all_docs_uri = join_url(&config.db_url, &format!("{}/_all_docs", &config.database));
+    let bulk_uri = join_url(&config.db_url, &format!("{}/_bulk_docs", &config.database));
+    let mut startkey: Option<String> = None;
+    let (mut migrated, mut skipped, mut collisions, mut failed) = (0, 0, 0, 0);
+    let mut migrate = || -> Result<(), FnError> { loop {
+        // Fetch the next page of documents
+        let cdb_resp = timings.db(|| cloudant_call("GET", || {
+            let request = config.request("GET", &all_docs_uri)
+                .query("include_docs", "true")
+                .query("limit", &(PAGE_SIZE + 1).to_string())
+                .set("Authorization", &authorization);
+            match &startkey {
+                Some(key) => request.query("startkey", &json!(key).to_string()),
+                None => request
+            }.call()
+        }))?;
+        let mut page = read_cloudant_json::<CDBResponse>(cdb_resp)
+            .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+        startkey = if page.rows.len() > PAGE_SIZE { page.rows.pop().map(|row| row.id) } else { None };
+
+        // Rename the field, design documents aside
+        let mut docs: Vec<sj::Map<String, sj::Value>> = Vec::new();
+        for mut row in page.rows.into_iter().filter(|row| !row.id.starts_with("_design/")) {
+            match rename_field(&mut row.doc, &from, &to) {
+                Rename::Renamed => docs.push(row.doc),
+                Rename::Absent => skipped += 1,
+                Rename::Collision => collisions += 1
+            }
+        }
+
+        // Write the page's renamed documents back
+        if !docs.is_empty() {
+            let payload = json!({"docs": docs});
+            let request = config.request("POST", &bulk_uri)
+                .set("Authorization", &authorization);
+            let cdb_resp = timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &payload)))?;
+            let results = read_cloudant_json::<Vec<CDBBulkResult>>(cdb_resp)
+                .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+            let ok = results.iter().filter(|res| res.ok == Some(true)).count();
+            migrated += ok;
+            failed += docs.len() - ok;
+        }
+
+        // No extra row: this was the last page
+        if startkey.is_none() {
+            return Ok(())
+        }
+    }};
+    if let Err(err) = migrate() {
+        return Err(err.with_fields(json!({
+            "migrated": migrated,
+            "skipped": skipped,
+            "collisions": collisions,
+            "failed": failed
+        })))
+    }
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "migrate_rename execution complete!",
+        "migrated": migrated,
+        "skipped": skipped,
+        "collisions": collisions,
+        "failed": failed
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_field_keeping_rev() {
+        let doc = |value: sj::Value| value.as_object().unwrap().clone();
+        let mut renamed = doc(json!({"_id": "a", "_rev": "1-abc", "status": "done"}));
+        assert_eq!(rename_field(&mut renamed, "status", "state"), Rename::Renamed);
+        assert_eq!(sj::Value::Object(renamed), json!({"_id": "a", "_rev": "1-abc", "state": "done"}));
+
+        let mut absent = doc(json!({"_id": "b", "_rev": "1-abc"}));
+        assert_eq!(rename_field(&mut absent, "status", "state"), Rename::Absent);
+        let mut both = doc(json!({"_id": "c", "status": 1, "state": 2}));
+        assert_eq!(rename_field(&mut both, "status", "state"), Rename::Collision);
+        assert_eq!(both["status"], 1);
+    }
+}
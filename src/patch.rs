@@ -0,0 +1,171 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, current_rev, decode_body, doc_url, ErrorCode, FnError, prelude, query_flag, query_param, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+/* Example decoded request body, the fields to change:
+{
+    "done": true,
+    "meta": {"priority": "high"}
+}
+Nested objects are merged field by field, any other value replaces the
+stored one. Fields left out keep their stored value. */
+type ICFRequestBody = sj::Map<String, sj::Value>;
+
+/* Example HTTP response from IBM Cloudant (document GET):
+{
+    "_id": "exampleid",
+    "_rev": "1-967a00dff5e02add41819138abb3284d",
+    "task": "write docs",
+    "done": false,
+    "meta": {"priority": "low", "owner": "jane"}
+} */
+type CDBDocument = sj::Map<String, sj::Value>;
+
+/* Example HTTP response from IBM Cloudant (document PUT):
+{
+    "id": "exampleid",
+    "ok": true,
+    "rev": "2-7051cbe5c8faecd085a3fa619e6e6337"
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    id: String,
+    ok: bool,
+    rev: String
+}
+
+// Deep-merge `patch` into `document`: objects present on both sides are
+// merged recursively, everything else in `patch` overrides
+fn deep_merge(document: &mut sj::Map<String, sj::Value>, patch: &sj::Map<String, sj::Value>) {
+    for (name, value) in patch {
+        match (document.get_mut(name), value) {
+            (Some(sj::Value::Object(stored)), sj::Value::Object(changes)) => deep_merge(stored, changes),
+            _ => {
+                document.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Read the document id from the query string
+    let id = match query_param(&i.__ow_query, "id") {
+        Some(id) if !id.is_empty() => id,
+        _ => {
+            // Document id not provided
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Missing id query param"))
+        }
+    };
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes, the id and rev are managed by the function
+    let mut changes: ICFRequestBody = sj::from_slice(&bytes)?;
+    changes.remove("_id");
+    changes.remove("_rev");
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Patch the document: fetch it, merge the changes in, then PUT the
+    // result with the fetched rev. If the document is updated concurrently
+    // between the GET and the PUT, Cloudant answers 409 and the whole
+    // fetch + merge + write is retried once.
+    let uri = doc_url(&config.db_url, &config.database, &id);
+    let mut retried = false;
+    let cdb_resp = loop {
+        // Fetch the current document, with its rev
+        let mut document = match timings.db(|| cloudant_call("GET", || config.request("GET", &uri)
+            .set("Authorization", &authorization)
+            .call())) {
+                Ok(res) => read_cloudant_json::<CDBDocument>(res)
+                    .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?,
+                Err(ureq::Error::Status(404, _)) => {
+                    // Nothing to patch
+                    // Return error message
+                    return Err(FnError::new(ErrorCode::NotFound, format!("Document not found: {}", id)))
+                },
+                Err(err) => {
+                    // Failure querying Cloudant, report its error body
+                    return Err(FnError::from(err))
+                }
+            };
+
+        // Write the merged document, carrying the fetched rev
+        deep_merge(&mut document, &changes);
+        let request = config.request("PUT", &uri)
+            .set("Authorization", &authorization);
+        match timings.db(|| cloudant_call("PUT", || send_json_body(request.clone(), &json!(document)))) {
+                Ok(res) => break res,
+                Err(ureq::Error::Status(409, _)) if !retried => retried = true,
+                Err(err @ ureq::Error::Status(409, _)) => {
+                    // Still conflicting after the retry, hand out the current rev
+                    let rev = current_rev(config.request("HEAD", &uri).set("Authorization", &authorization));
+                    return Err(FnError::conflict(err, rev))
+                },
+                Err(err) => {
+                    // Failure querying Cloudant, report its error body
+                    return Err(FnError::from(err))
+                }
+            }
+    };
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "patch execution complete!",
+        "patched_record": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_nested_objects() {
+        let mut document = json!({
+            "_id": "exampleid",
+            "task": "write docs",
+            "done": false,
+            "meta": {"priority": "low", "owner": "jane"}
+        });
+        let changes = json!({"done": true, "meta": {"priority": "high"}, "tags": ["docs"]});
+        deep_merge(document.as_object_mut().unwrap(), changes.as_object().unwrap());
+        assert_eq!(document, json!({
+            "_id": "exampleid",
+            "task": "write docs",
+            "done": true,
+            "meta": {"priority": "high", "owner": "jane"},
+            "tags": ["docs"]
+        }));
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::Instant;
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{Config, FnError, respond_result, server_prelude, traced};
+
+
+// A probe is answered as soon as Cloudant answers at all: any HTTP status,
+// 401 included, means the network and DNS are fine. Only transport errors
+// (DNS, connection, TLS, timeout) make it unreachable.
+fn reachable(result: &Result<ureq::Response, ureq::Error>) -> Result<u16, String> {
+    match result {
+        Ok(res) => Ok(res.status()),
+        Err(ureq::Error::Status(code, _)) => Ok(*code),
+        Err(err) => Err(err.to_string())
+    }
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, no database needed at the server level
+    let (_, config) = server_prelude()?;
+
+    Ok(probe(&config))
+}
+
+// Reach the Cloudant server root without credentials: no IAM round trip,
+// unlike `health`. Probes want a prompt answer, so it is not retried. An
+// unreachable Cloudant is reported in the body, with the error, rather than
+// as an error response.
+fn probe(config: &Config) -> sj::Value {
+    let started = Instant::now();
+    let result = traced("cloudant", "HEAD", || config.request("HEAD", &config.db_url).call());
+    let latency_ms = started.elapsed().as_millis() as u64;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "ping execution complete!",
+        "latency_ms": latency_ms
+    });
+    match reachable(&result) {
+        Ok(status) => {
+            body["reachable"] = json!(true);
+            body["status"] = json!(status);
+        },
+        Err(msg) => {
+            // Network, DNS or TLS failure
+            body["reachable"] = json!(false);
+            body["error"] = json!(msg);
+        }
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn any_http_status_is_reachable() {
+        let ok = Ok(ureq::Response::new(200, "OK", "").unwrap());
+        assert_eq!(reachable(&ok), Ok(200));
+        let unauthorized = Err(ureq::Error::Status(401, ureq::Response::new(401, "Unauthorized", "").unwrap()));
+        assert_eq!(reachable(&unauthorized), Ok(401));
+        let refused = ureq::get("http://127.0.0.1:1/").call();
+        assert!(reachable(&refused).is_err());
+    }
+
+    fn config(db_url: &str) -> Config {
+        Config {
+            iam_apikey: None,
+            username: None,
+            password: None,
+            db_url: db_url.to_string(),
+            database: String::new(),
+            headers: Vec::new()
+        }
+    }
+
+    #[test]
+    fn reports_unreachable_in_the_body() {
+        let body = probe(&config("http://127.0.0.1:1"));
+        assert_eq!(body["err"], false);
+        assert_eq!(body["reachable"], false);
+        assert!(body["error"].as_str().unwrap().contains("127.0.0.1:1"), "{}", body);
+        assert!(body["latency_ms"].is_u64() && body.get("status").is_none());
+
+        let mut server = mockito::Server::new();
+        server.mock("HEAD", "/").with_status(401).create();
+        let body = probe(&config(&server.url()));
+        assert_eq!(body["reachable"], true);
+        assert_eq!(body["status"], 401);
+    }
+}
@@ -0,0 +1,115 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{admin_enabled, cloudant_call, decode_body, ErrorCode, FnError, join_url, prelude, query_flag, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (`_purge`):
+{
+    "purge_seq": null,
+    "purged": {
+        "exampleid": ["2-7051cbe5c8faecd085a3fa619e6e6337"]
+    }
+}
+Purging removes the revisions outright, tombstones included, unlike a
+delete. It is not replicated: replicas of the database keep the purged
+revisions, and replicating from one of them brings the documents back.
+Purge every replica, or stop replicating from them, before relying on it. */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    purge_seq: Option<sj::Value>,
+    purged: sj::Map<String, sj::Value>
+}
+
+// The request body maps document ids to the revisions to purge, e.g.
+// `{"exampleid": ["2-7051cbe5c8faecd085a3fa619e6e6337"]}`
+fn validate_purge(body: &sj::Value) -> Result<(), String> {
+    let ids = match body.as_object() {
+        Some(ids) if !ids.is_empty() => ids,
+        _ => return Err("Failure validating purge request: expected a non-empty object of {id: [rev, ...]}".to_string())
+    };
+    for (id, revs) in ids {
+        let valid = revs.as_array()
+            .is_some_and(|revs| !revs.is_empty() && revs.iter().all(|rev| rev.as_str().is_some_and(|rev| !rev.is_empty())));
+        if !valid {
+            return Err(format!("Failure validating purge request: expected a non-empty list of revs for {}", id))
+        }
+    }
+    Ok(())
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Purging is an admin operation, opted into per deployment
+    if !admin_enabled() {
+        // Admin operations not enabled for this function
+        // Return error message
+        return Err(FnError::new(ErrorCode::Forbidden, "Refusing to purge: set ADMIN_ENABLED=true to allow admin operations"))
+    }
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes
+    let revs: sj::Value = sj::from_slice(&bytes)?;
+    if let Err(msg) = validate_purge(&revs) {
+        // Not a map of ids to revs
+        // Return error message
+        return Err(FnError::new(ErrorCode::ValidationError, msg))
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Purge the revisions
+    let uri = join_url(&config.db_url, &format!("{}/_purge", &config.database));
+    let request = config.request("POST", &uri)
+        .set("Authorization", &authorization);
+    let cdb_resp = timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &revs)))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "purge execution complete!",
+        "purge_seq": cdb_data.purge_seq,
+        "purged": cdb_data.purged
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_revs_by_id() {
+        assert!(validate_purge(&json!({"exampleid": ["2-7051cbe5c8faecd085a3fa619e6e6337"]})).is_ok());
+        assert!(validate_purge(&json!({})).is_err());
+        assert!(validate_purge(&json!(["exampleid"])).is_err());
+        assert!(validate_purge(&json!({"exampleid": []})).is_err());
+        assert!(validate_purge(&json!({"exampleid": "2-7051cbe5c8faecd085a3fa619e6e6337"})).is_err());
+        assert!(validate_purge(&json!({"exampleid": [""]})).is_err());
+    }
+}
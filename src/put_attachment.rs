@@ -0,0 +1,92 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{attachment_url, cloudant_call, decode_body, decode_optional_body, ErrorCode, FnError, prelude, query_flag, read_cloudant_json, respond_result, Timings};
+
+
+/* Example decoded request body:
+{
+    "_id": "exampleid",
+    "_rev": "1-967a00dff5e02add41819138abb3284d",
+    "name": "photo.png",
+    "content_type": "image/png",
+    "data": "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg=="
+}
+`_rev` may be omitted to create a new document holding the attachment. */
+#[derive(Deserialize, Debug)]
+struct ICFRequestBody {
+    _id: String,
+    _rev: Option<String>,
+    name: String,
+    content_type: String,
+    data: String
+}
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "id": "exampleid",
+    "ok": true,
+    "rev": "2-7051cbe5c8faecd085a3fa619e6e6337"
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    id: String,
+    ok: bool,
+    rev: String
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes
+    let attachment: ICFRequestBody = sj::from_slice(&bytes)?;
+
+    // Decode the attachment bytes, subject to the same size limit as bodies;
+    // empty attachments are allowed
+    let data = decode_optional_body(&attachment.data)?.unwrap_or_default();
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Upload the attachment to Cloudant
+    let uri = attachment_url(&config.db_url, &config.database, &attachment._id, &attachment.name);
+    let mut request = config.request("PUT", &uri)
+        .set("Authorization", &authorization)
+        .set("Content-Type", &attachment.content_type);
+    if let Some(rev) = &attachment._rev {
+        request = request.query("rev", rev);
+    }
+    let cdb_resp = timings.db(|| cloudant_call("PUT", || request.clone().send_bytes(&data)))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "put_attachment execution complete!",
+        "rev": &cdb_data.rev,
+        "attachment": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
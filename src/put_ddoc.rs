@@ -0,0 +1,132 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, current_rev, decode_body, doc_url, ErrorCode, FnError, prelude, query_flag, query_param, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (design document PUT):
+{
+    "id": "_design/tasks",
+    "ok": true,
+    "rev": "2-7051cbe5c8faecd085a3fa619e6e6337"
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    id: String,
+    ok: bool,
+    rev: String
+}
+
+// The design document id: the body's `_id`, or else the `id` query param.
+// Either way it must name a design document, `_design/{name}`.
+fn design_id(document: &sj::Map<String, sj::Value>, query_id: Option<String>) -> Result<String, String> {
+    let id = match document.get("_id") {
+        Some(sj::Value::String(id)) => id.clone(),
+        Some(_) => return Err("Invalid _id: expected a string".to_string()),
+        None => query_id.ok_or_else(|| "Missing _id: expected a design document _id or id query param".to_string())?
+    };
+    match id.strip_prefix("_design/") {
+        Some(name) if !name.is_empty() => Ok(id),
+        _ => Err(format!("Invalid _id: {} is not a design document id (_design/{{name}})", id))
+    }
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes
+    let document: sj::Value = sj::from_slice(&bytes)?;
+
+    // The design document must be a JSON object, e.g. holding `views`
+    let mut document = match document {
+        sj::Value::Object(map) => map,
+        _ => {
+            // Design document is not a JSON object
+            // Return error message
+            return Err(FnError::new(ErrorCode::ValidationError, "Failure validating design document: expected a JSON object"))
+        }
+    };
+    // Fails on a missing id, or one that isn't a design document's
+    let id = design_id(&document, query_param(&i.__ow_query, "id"))
+        .map_err(|msg| FnError::new(ErrorCode::ValidationError, msg))?;
+    document.insert("_id".to_string(), json!(id));
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Without a `_rev` in the body, look up the current one so an existing
+    // design document is updated; none means it is created
+    let uri = doc_url(&config.db_url, &config.database, &id);
+    if !document.contains_key("_rev") {
+        let rev = timings.db(|| current_rev(config.request("HEAD", &uri).set("Authorization", &authorization)));
+        if let Some(rev) = rev {
+            document.insert("_rev".to_string(), json!(rev));
+        }
+    }
+    let updated = document.contains_key("_rev");
+
+    // Write the design document
+    let request = config.request("PUT", &uri)
+        .set("Authorization", &authorization);
+    let cdb_resp = match timings.db(|| cloudant_call("PUT", || send_json_body(request.clone(), &json!(document)))) {
+            Ok(res) => res,
+            Err(err @ ureq::Error::Status(409, _)) => {
+                // Stale `_rev`, or updated concurrently: hand out the current rev
+                let rev = current_rev(config.request("HEAD", &uri).set("Authorization", &authorization));
+                return Err(FnError::conflict(err, rev))
+            },
+            Err(err) => {
+                // Failure querying Cloudant, report its error body
+                return Err(FnError::from(err))
+            }
+        };
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "put_ddoc execution complete!",
+        "operation": if updated { "update" } else { "create" },
+        "id": cdb_data.id,
+        "rev": cdb_data.rev
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_design_document_id() {
+        let doc = |value: sj::Value| value.as_object().unwrap().clone();
+        assert_eq!(design_id(&doc(json!({"_id": "_design/tasks"})), None), Ok("_design/tasks".to_string()));
+        assert_eq!(design_id(&doc(json!({})), Some("_design/tasks".to_string())), Ok("_design/tasks".to_string()));
+        assert!(design_id(&doc(json!({"_id": "tasks"})), None).is_err());
+        assert!(design_id(&doc(json!({"_id": "_design/"})), None).is_err());
+        assert!(design_id(&doc(json!({})), None).is_err());
+    }
+}
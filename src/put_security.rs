@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{admin_enabled, cloudant_call, decode_body, ErrorCode, FnError, join_url, prelude, query_flag, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+/* Example request body, the whole security document: it replaces the
+current one, so fields left out are cleared:
+{
+    "admins": {"names": ["admin"], "roles": []},
+    "members": {"names": ["reader"], "roles": ["team"]},
+    "cloudant": {"nobody": ["_reader"], "apikey-abc": ["_reader", "_writer"]}
+}
+`cloudant` holds the permissions granted through the Cloudant dashboard,
+by user or API key, next to CouchDB's own `admins` and `members`. */
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+struct CDBSecurity {
+    #[serde(default)]
+    admins: CDBRoles,
+    #[serde(default)]
+    members: CDBRoles,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cloudant: Option<BTreeMap<String, Vec<String>>>
+}
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+struct CDBRoles {
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    roles: Vec<String>
+}
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "ok": true
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    ok: bool
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Changing access is an admin operation, opted into per deployment
+    if !admin_enabled() {
+        // Admin operations not enabled for this function
+        // Return error message
+        return Err(FnError::new(ErrorCode::Forbidden, "Refusing to update security: set ADMIN_ENABLED=true to allow admin operations"))
+    }
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes, checking the security document's shape
+    let security: CDBSecurity = sj::from_slice(&bytes)?;
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Replace the security document of the database
+    let uri = join_url(&config.db_url, &format!("{}/_security", &config.database));
+    let request = config.request("PUT", &uri)
+        .set("Authorization", &authorization);
+    let payload = json!(security);
+    let cdb_resp = timings.db(|| cloudant_call("PUT", || send_json_body(request.clone(), &payload)))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "put_security execution complete!",
+        "ok": cdb_data.ok,
+        "security": security
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_malformed_security() {
+        assert!(sj::from_str::<CDBSecurity>(r#"{"members": {"names": ["reader"]}}"#).is_ok());
+        assert!(sj::from_str::<CDBSecurity>(r#"{"members": {"names": "reader"}}"#).is_err());
+        assert!(sj::from_str::<CDBSecurity>(r#"{"cloudant": {"nobody": "_reader"}}"#).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, decode_body, ErrorCode, FnError, join_url, query_flag, read_cloudant_json, respond_result, send_json_body, server_prelude, Timings};
+
+
+/* Example decoded request body:
+{
+    "source": "https://<account>.cloudantnosqldb.appdomain.cloud/todos",
+    "target": "https://<account>.cloudantnosqldb.appdomain.cloud/todos-backup",
+    "doc_ids": ["exampleid"],
+    "create_target": true
+}
+Without `doc_ids` or `selector` the whole source database is replicated;
+at most one of them may be provided. `create_target` creates the target
+database when it doesn't exist yet. */
+#[derive(Deserialize, Serialize, Debug)]
+struct ICFRequestBody {
+    source: String,
+    target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    doc_ids: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selector: Option<sj::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    create_target: Option<bool>
+}
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "history": [ ... ],
+    "ok": true,
+    "replication_id_version": 4,
+    "session_id": "4e1b0b1ee3b8b3fd2b8e4c58a7d1e12b",
+    "source_last_seq": "1-g1AAAA..."
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    ok: bool,
+    session_id: String
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, no database needed at the server level
+    let (i, config) = server_prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes
+    let document: ICFRequestBody = sj::from_slice(&bytes)?;
+
+    // Validate the optional replication filter: doc_ids or selector, not both
+    let filter_err = match (&document.doc_ids, &document.selector) {
+        (Some(_), Some(_)) => Some("Provide either doc_ids or selector, not both"),
+        (Some(ids), None) if ids.is_empty() => Some("doc_ids must not be empty"),
+        _ => None
+    };
+    if let Some(msg) = filter_err {
+        // Invalid replication filter
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, msg))
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Trigger the one-shot replication on Cloudant
+    let uri = join_url(&config.db_url, "_replicate");
+    let payload = json!(document);
+    let request = config.request("POST", &uri)
+        .set("Authorization", &authorization);
+    let cdb_resp = timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &payload)))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "replicate execution complete!",
+        "replication": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
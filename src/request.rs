@@ -0,0 +1,96 @@
+use serde::Deserialize;
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, decode_body, ErrorCode, FnError, join_url, prelude, query_flag, respond_result, send_json_body, Timings};
+
+
+// Methods the passthrough may use, anything else is rejected
+const ALLOWED_METHODS: [&str; 5] = ["GET", "POST", "PUT", "DELETE", "HEAD"];
+
+/* Example decoded request body:
+{
+    "method": "GET",
+    "path": "todos/_design_docs",
+    "body": {"keys": ["_design/app"]}
+}
+`path` is relative to `db_url`, `body` is optional and sent as JSON. */
+#[derive(Deserialize, Debug)]
+struct ICFRequestBody {
+    method: String,
+    path: String,
+    body: Option<sj::Value>
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes
+    let proxied: ICFRequestBody = sj::from_slice(&bytes)?;
+
+    // Only the allowlisted methods can be proxied
+    let method = proxied.method.to_ascii_uppercase();
+    if !ALLOWED_METHODS.contains(&method.as_str()) {
+        // Method outside the allowlist
+        // Return error message
+        return Err(FnError::new(ErrorCode::BadRequest, format!("Unsupported method: {}, expected one of {}", proxied.method, ALLOWED_METHODS.join(", "))))
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Proxy the call to Cloudant, non-2xx answers included
+    let uri = join_url(&config.db_url, &proxied.path);
+    let request = config.request(&method, &uri)
+        .set("Authorization", &authorization);
+    let cdb_resp = match timings.db(|| cloudant_call(&method, || match &proxied.body {
+        Some(body) => send_json_body(request.clone(), body),
+        None => request.clone().call()
+    })) {
+            Ok(res) => res,
+            Err(ureq::Error::Status(_, res)) => res,
+            Err(err) => {
+                // Failure reaching Cloudant
+                return Err(FnError::from(err))
+            }
+        };
+
+    // Return Cloudant's body as is: JSON when it parses, text otherwise
+    let status = cdb_resp.status();
+    let raw = match cdb_resp.into_string() {
+        Ok(res) => res,
+        Err(err) => {
+            // Failure reading Cloudant response
+            // Return error message
+            return Err(FnError::new(ErrorCode::CloudantError, format!("Failure reading Cloudant response (status {}): {}", status, err)))
+        }
+    };
+    let response = sj::from_str::<sj::Value>(&raw).unwrap_or(sj::Value::String(raw));
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "request execution complete!",
+        "status": status,
+        "response": response
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
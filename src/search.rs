@@ -0,0 +1,129 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, ErrorCode, FnError, join_url, path_segment, prelude, query_flag, query_param, read_cloudant_json, respond_result, Timings};
+
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "total_rows": 1,
+    "bookmark": "g1AAAAB3eJzLYWBgYMpgTmHgz8tPSTV0MDQy0TM",
+    "rows": [
+        {
+            "id": "exampleid",
+            "order": [1.2, 0],
+            "fields": {"task": "write docs"}
+        }
+    ]
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    total_rows: i64,
+    bookmark: Option<String>,
+    rows: Vec<sj::Value>
+}
+
+// Add the search params to `request`. They go through ureq's query
+// encoding, so Lucene syntax (`+`, `"`, `:`, `*`, spaces, ...) reaches
+// Cloudant intact.
+fn with_params(request: ureq::Request, params: &[(&str, String)]) -> ureq::Request {
+    params.iter().fold(request, |req, (name, value)| req.query(name, value))
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Read the design document and search index names from the query string
+    let (design, index) = match (
+        query_param(&i.__ow_query, "design"),
+        query_param(&i.__ow_query, "index")
+    ) {
+        (Some(d), Some(x)) if !d.is_empty() && !x.is_empty() => (d, x),
+        _ => {
+            // Design document or index name not provided
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Missing design or index query param"))
+        }
+    };
+
+    // Both names stay single path segments of the database, see
+    // `path_segment`
+    let segment = |name: &str| path_segment(name).map_err(|msg| FnError::new(ErrorCode::BadRequest, msg));
+    let path = format!("{}/_design/{}/_search/{}", &config.database, segment(&design)?, segment(&index)?);
+
+    // The Lucene query, plus the optional paging params
+    let mut params: Vec<(&str, String)> = match query_param(&i.__ow_query, "query") {
+        Some(query) if !query.trim().is_empty() => vec![("query", query)],
+        _ => {
+            // Nothing to search for
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Missing query query param"))
+        }
+    };
+    if let Some(limit) = query_param(&i.__ow_query, "limit") {
+        if limit.parse::<u32>().is_err() {
+            // Limit that isn't a row count
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Invalid limit query param: expected a non-negative integer"))
+        }
+        params.push(("limit", limit));
+    }
+    if let Some(bookmark) = query_param(&i.__ow_query, "bookmark") {
+        params.push(("bookmark", bookmark));
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Query the Cloudant search index
+    let uri = join_url(&config.db_url, &path);
+    let cdb_resp = timings.db(|| cloudant_call("GET", || with_params(config.request("GET", &uri), &params)
+        .set("Authorization", &authorization)
+        .call()))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct, the bookmark fetches the next page
+    let mut body = json!({
+        "err": false,
+        "msg": "search execution complete!",
+        "total_rows": cdb_data.total_rows,
+        "bookmark": cdb_data.bookmark,
+        "rows": cdb_data.rows
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_lucene_query() {
+        let params = [("query", "task:\"write docs\" AND +done:false".to_string())];
+        let request = with_params(ureq::get("https://acct.cloudant.com/todos/_design/app/_search/tasks"), &params);
+        assert_eq!(
+            request.url(),
+            "https://acct.cloudant.com/todos/_design/app/_search/tasks?query=task%3A%22write+docs%22+AND+%2Bdone%3Afalse"
+        );
+    }
+}
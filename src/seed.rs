@@ -0,0 +1,117 @@
+use serde::Deserialize;
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, decode_optional_body, ErrorCode, FnError, join_url, prelude, query_flag, query_param, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+// Most documents seeded by one invocation, generated or given
+const MAX_SEED_DOCS: usize = 1000;
+
+/* Example HTTP response from IBM Cloudant (`_bulk_docs`):
+[
+    {"ok": true, "id": "exampleid", "rev": "1-967a00dff5e02add41819138abb3284d"},
+    {"id": "otherid", "error": "conflict", "reason": "Document update conflict."}
+] */
+#[derive(Deserialize, Debug)]
+struct CDBBulkResult {
+    error: Option<String>
+}
+
+// The documents to seed: the array in the body, or `count` synthetic to-do
+// items when there is no body
+fn seed_documents(body: Option<sj::Value>, count: Option<String>) -> Result<Vec<sj::Value>, String> {
+    let documents = match (body, count) {
+        (Some(sj::Value::Array(documents)), None) => documents,
+        (Some(_), None) => return Err("Invalid body: expected an array of documents".to_string()),
+        (None, Some(count)) => match count.parse::<usize>() {
+            Ok(count) if count > 0 && count <= MAX_SEED_DOCS => (1..=count)
+                .map(|n| json!({"task": format!("Seed task {}", n), "done": n % 2 == 0}))
+                .collect(),
+            _ => return Err(format!("Invalid count query param: {}, expected 1 to {}", count, MAX_SEED_DOCS))
+        },
+        (Some(_), Some(_)) => return Err("Provide either an array of documents or a count query param, not both".to_string()),
+        (None, None) => return Err("Missing documents: provide an array of documents or a count query param".to_string())
+    };
+    if documents.is_empty() || documents.len() > MAX_SEED_DOCS {
+        return Err(format!("Invalid documents: expected 1 to {} documents, got {}", MAX_SEED_DOCS, documents.len()))
+    }
+    if !documents.iter().all(|document| document.is_object()) {
+        return Err("Invalid documents: every document must be a JSON object".to_string())
+    }
+    Ok(documents)
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Decode the optional request body from base64: fixture documents, or
+    // nothing when generating them with `count`
+    let body = match decode_optional_body(&i.__ow_body)? {
+        Some(bytes) => Some(sj::from_slice::<sj::Value>(&bytes)?),
+        None => None
+    };
+    let documents = seed_documents(body, query_param(&i.__ow_query, "count"))
+        .map_err(|msg| FnError::new(ErrorCode::BadRequest, msg))?;
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Write every document in one `_bulk_docs` request
+    let uri = join_url(&config.db_url, &format!("{}/_bulk_docs", &config.database));
+    let request = config.request("POST", &uri)
+        .set("Authorization", &authorization);
+    let payload = json!({"docs": documents});
+    let cdb_resp = timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &payload)))?;
+
+    // Deserialize Cloudant response
+    let results = read_cloudant_json::<Vec<CDBBulkResult>>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+    let created = results.iter().filter(|res| res.error.is_none()).count();
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "seed execution complete!",
+        "created": created,
+        "failed": results.len() - created
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_or_takes_documents() {
+        let documents = seed_documents(None, Some("3".to_string())).unwrap();
+        assert_eq!(documents.len(), 3);
+        assert_eq!(documents[0], json!({"task": "Seed task 1", "done": false}));
+        let given = json!([{"task": "write docs", "done": false}]);
+        assert_eq!(seed_documents(Some(given), None).unwrap().len(), 1);
+
+        assert!(seed_documents(None, Some("0".to_string())).is_err());
+        assert!(seed_documents(None, Some((MAX_SEED_DOCS + 1).to_string())).is_err());
+        assert!(seed_documents(Some(json!([])), None).is_err());
+        assert!(seed_documents(Some(json!([1])), None).is_err());
+        assert!(seed_documents(Some(json!({"task": "a"})), None).is_err());
+        assert!(seed_documents(None, None).is_err());
+    }
+}
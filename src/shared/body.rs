@@ -0,0 +1,186 @@
+use std::{env, fmt};
+use serde_json::{self as sj};
+use url::form_urlencoded;
+use crate::error::ErrorCode;
+
+// Default cap on the decoded request body, overridable via `MAX_BODY_BYTES`
+pub const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+pub fn max_body_bytes() -> Result<usize, BodyError> {
+    match env::var("MAX_BODY_BYTES") {
+        Ok(value) => value.trim().parse::<usize>()
+            .map_err(|_| BodyError::InvalidLimit(value)),
+        Err(_) => Ok(MAX_BODY_BYTES)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BodyError {
+    Missing,
+    TooLarge { size: usize, max: usize },
+    InvalidBase64(base64::DecodeError),
+    InvalidLimit(String)
+}
+
+impl BodyError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            BodyError::Missing => ErrorCode::MissingBody,
+            BodyError::TooLarge { .. } => ErrorCode::PayloadTooLarge,
+            BodyError::InvalidBase64(_) => ErrorCode::BadBase64,
+            BodyError::InvalidLimit(_) => ErrorCode::ConfigError
+        }
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BodyError::Missing => write!(f, "Missing request body"),
+            BodyError::TooLarge { size, max } => write!(
+                f, "Payload too large: body of ~{} bytes exceeds the {} bytes limit", size, max
+            ),
+            BodyError::InvalidBase64(err) => write!(f, "Failure decoding base64 body: {}", err),
+            BodyError::InvalidLimit(value) => write!(f, "Invalid MAX_BODY_BYTES: {}", value)
+        }
+    }
+}
+
+// Decode the base64 `__ow_body` of a web action, refusing bodies larger
+// than `MAX_BODY_BYTES` before anything is allocated for the decoded bytes.
+// For functions that need a body: an empty `__ow_body` is an error.
+pub fn decode_body(body: &str) -> Result<Vec<u8>, BodyError> {
+    decode_body_with_limit(body, max_body_bytes()?)
+}
+
+// Same as `decode_body` for functions whose body is optional: an empty
+// `__ow_body` (e.g. a GET) is no body at all rather than a decoding error
+pub fn decode_optional_body(body: &str) -> Result<Option<Vec<u8>>, BodyError> {
+    if body.trim().is_empty() {
+        return Ok(None)
+    }
+    decode_body(body).map(Some)
+}
+
+fn decode_body_with_limit(body: &str, max_bytes: usize) -> Result<Vec<u8>, BodyError> {
+    if body.trim().is_empty() {
+        return Err(BodyError::Missing)
+    }
+    // Every 4 base64 characters decode to at most 3 bytes
+    let decoded_len = body.len().div_ceil(4) * 3;
+    if decoded_len > max_bytes {
+        return Err(BodyError::TooLarge { size: decoded_len, max: max_bytes })
+    }
+    decode_base64(body).map_err(BodyError::InvalidBase64)
+}
+
+// Gateways don't agree on a base64 flavor: try padded standard, then
+// unpadded, then URL-safe, and report the standard error if none fits
+const BASE64_CONFIGS: [base64::Config; 4] = [
+    base64::STANDARD,
+    base64::STANDARD_NO_PAD,
+    base64::URL_SAFE,
+    base64::URL_SAFE_NO_PAD
+];
+
+fn decode_base64(body: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    let standard = base64::decode_config(body, BASE64_CONFIGS[0]);
+    if standard.is_ok() {
+        return standard
+    }
+    BASE64_CONFIGS[1..].iter()
+        .find_map(|&config| base64::decode_config(body, config).ok())
+        .ok_or_else(|| standard.unwrap_err())
+}
+
+// Media type of the request from `__ow_headers["content-type"]`, lowercased
+// and without parameters (`application/json; charset=utf-8` ->
+// `application/json`). Requests without the header are treated as JSON.
+pub fn content_type(headers: &sj::Value) -> String {
+    headers.get("content-type")
+        .and_then(|value| value.as_str())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .unwrap_or_else(|| "application/json".to_string())
+}
+
+// Turn a form-encoded body (`task=write+docs&done=false`) into a JSON
+// object. Form values are always text, so `true`/`false` become booleans
+// to fit typed documents; every other value stays a string.
+pub fn form_to_json(bytes: &[u8]) -> sj::Value {
+    let map = form_urlencoded::parse(bytes)
+        .map(|(key, value)| {
+            let value = match value.as_ref() {
+                "true" => sj::Value::Bool(true),
+                "false" => sj::Value::Bool(false),
+                _ => sj::Value::String(value.into_owned())
+            };
+            (key.into_owned(), value)
+        })
+        .collect();
+    sj::Value::Object(map)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_body_within_limit() {
+        let body = base64::encode(r#"{"task": "write docs", "done": false}"#);
+        let bytes = decode_body_with_limit(&body, MAX_BODY_BYTES).unwrap();
+        assert_eq!(bytes, br#"{"task": "write docs", "done": false}"#.to_vec());
+    }
+
+    #[test]
+    fn rejects_oversized_body() {
+        let body = base64::encode(vec![b'x'; MAX_BODY_BYTES + 1]);
+        let err = decode_body_with_limit(&body, MAX_BODY_BYTES).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::PayloadTooLarge);
+        assert!(err.to_string().starts_with("Payload too large"), "{}", err);
+    }
+
+    #[test]
+    fn reads_media_type() {
+        let headers = sj::json!({"content-type": "Application/JSON; charset=utf-8"});
+        assert_eq!(content_type(&headers), "application/json");
+        assert_eq!(content_type(&sj::json!({})), "application/json");
+    }
+
+    #[test]
+    fn parses_form_body() {
+        let doc = form_to_json(b"task=write+docs%21&done=false");
+        assert_eq!(doc, sj::json!({"task": "write docs!", "done": false}));
+    }
+
+    #[test]
+    fn reports_invalid_base64() {
+        let err = decode_body_with_limit("not base64!", MAX_BODY_BYTES).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::BadBase64);
+        assert!(err.to_string().starts_with("Failure decoding base64 body"), "{}", err);
+    }
+
+    #[test]
+    fn decodes_base64_flavors() {
+        // 0xfb 0xff 0xfe differ between the standard and URL-safe alphabets
+        let bytes = b"{\"x\":\"\xfb\xff\xfe\"}".to_vec();
+        for config in &BASE64_CONFIGS {
+            let body = base64::encode_config(&bytes, *config);
+            assert_eq!(decode_body_with_limit(&body, MAX_BODY_BYTES), Ok(bytes.clone()), "{}", body);
+        }
+    }
+
+    #[test]
+    fn requires_non_empty_body() {
+        assert_eq!(decode_body_with_limit("", MAX_BODY_BYTES), Err(BodyError::Missing));
+        assert_eq!(decode_body_with_limit("  ", MAX_BODY_BYTES), Err(BodyError::Missing));
+        assert_eq!(BodyError::Missing.to_string(), "Missing request body");
+    }
+
+    #[test]
+    fn treats_empty_optional_body_as_none() {
+        assert_eq!(decode_optional_body(""), Ok(None));
+        assert_eq!(decode_optional_body(&base64::encode("{}")), Ok(Some(b"{}".to_vec())));
+    }
+}
@@ -0,0 +1,494 @@
+use std::{env, io::Read, thread, time::Duration};
+use serde::de::DeserializeOwned;
+use serde_json::{self as sj};
+use crate::deadline::remaining;
+use crate::log::traced;
+use crate::query::query_param;
+use crate::rate_limit::acquire;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+// Characters left as-is when a document id is used as a URL path segment
+const DOC_ID: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+// Resolve the Cloudant URL to use for requests. When the private endpoint
+// is enabled (payload `use_private_endpoint` wins over the
+// `USE_PRIVATE_ENDPOINT` env var), the public hostname is rewritten to
+// its private service endpoint, see `private_endpoint`.
+pub fn resolve_db_url(db_url: &str, use_private_endpoint: Option<bool>) -> String {
+    let enabled = use_private_endpoint.unwrap_or_else(|| {
+        matches!(env::var("USE_PRIVATE_ENDPOINT").as_deref(), Ok("1") | Ok("true"))
+    });
+    if enabled {
+        private_endpoint(db_url)
+    } else {
+        db_url.to_string()
+    }
+}
+
+// Hostname transformation rule: a `private` label is inserted right after
+// the first (account) label of the host, everything else is kept as is:
+//   https://acct.cloudantnosqldb.appdomain.cloud/db
+//     -> https://acct.private.cloudantnosqldb.appdomain.cloud/db
+//   https://acct.cloudant.com -> https://acct.private.cloudant.com
+// URLs whose second label is already `private`, or whose host has a
+// single label (e.g. `localhost`), are returned unchanged.
+pub fn private_endpoint(db_url: &str) -> String {
+    let (scheme, rest) = match db_url.find("://") {
+        Some(idx) => db_url.split_at(idx + 3),
+        None => ("", db_url)
+    };
+    let host_end = rest.find(['/', ':']).unwrap_or(rest.len());
+    let (host, tail) = rest.split_at(host_end);
+
+    let mut labels: Vec<&str> = host.split('.').collect();
+    if labels.len() < 2 || labels[1] == "private" {
+        return db_url.to_string()
+    }
+    labels.insert(1, "private");
+    format!("{}{}{}", scheme, labels.join("."), tail)
+}
+
+// Database to target: the `db` param of the request's query string, used by
+// multi-tenant setups, overrides the configured `database`. Overrides must
+// be valid Cloudant database names (a lowercase letter, followed by
+// lowercase letters, digits and `_$()+/-`). The name is returned ready to
+// be used as a URL path segment, i.e. with `/` escaped as `%2F`.
+pub fn resolve_database(database: &str, query: &str) -> Result<String, String> {
+    let name = match query_param(query, "db") {
+        Some(name) => name,
+        None => return Ok(database.replace('/', "%2F"))
+    };
+    if !valid_database_name(&name) {
+        return Err(format!("Invalid db query param: {} is not a valid database name", name))
+    }
+    Ok(name.replace('/', "%2F"))
+}
+
+// Whether `name` is a valid Cloudant database name, see `resolve_database`
+pub fn valid_database_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some('a'..='z'))
+        && chars.all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_' | '$' | '(' | ')' | '+' | '/' | '-'))
+}
+
+// Join a base URL and a path with exactly one `/` between them, whatever
+// slashes the configured `db_url` ends with or the path starts with
+pub fn join_url(base: &str, path: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), path.trim_start_matches('/'))
+}
+
+// Build the URL of a single document, percent-encoding its id so ids
+// containing `/`, `?` or spaces stay a single path segment. The `_design/`
+// prefix of design document ids is kept verbatim, as Cloudant expects.
+pub fn doc_url(db_url: &str, database: &str, id: &str) -> String {
+    let (prefix, name) = match id.strip_prefix("_design/") {
+        Some(name) => ("_design/", name),
+        None => ("", id)
+    };
+    join_url(db_url, &format!("{}/{}{}", database, prefix, utf8_percent_encode(name, DOC_ID)))
+}
+
+// Percent-encode a design document, view or index name from the query
+// string so it stays one path segment of the configured database. `.` and
+// `..` are refused: URL parsers resolve them, even percent-encoded.
+pub fn path_segment(name: &str) -> Result<String, String> {
+    if matches!(name, "." | "..") {
+        return Err(format!("Invalid name {}: expected a design document, view or index name", name))
+    }
+    Ok(utf8_percent_encode(name, DOC_ID).to_string())
+}
+
+// Build the URL of the attachment `name` of a document
+pub fn attachment_url(db_url: &str, database: &str, id: &str, name: &str) -> String {
+    format!("{}/{}", doc_url(db_url, database, id), utf8_percent_encode(name, DOC_ID))
+}
+
+// Cloudant reports a document's current rev as its quoted `ETag` header,
+// e.g. `"1-967a00dff5e02add41819138abb3284d"`
+pub fn etag_rev(etag: &str) -> String {
+    etag.trim().trim_start_matches("W/").trim_matches('"').to_string()
+}
+
+// Retries of a Cloudant request answered with 429 or 5xx
+pub const MAX_RETRIES: u32 = 3;
+// Backoff before the first retry, doubled for each following one
+const RETRY_BASE_MS: u64 = 100;
+// Longest `Retry-After` honored, so a retry can't outlive the activation
+const RETRY_AFTER_MAX_SECS: u64 = 10;
+
+// Run a Cloudant request through `traced`, retrying it up to `MAX_RETRIES`
+// times when Cloudant is rate limiting (429 `too_many_requests`) or failing
+// (5xx), see `retryable` for which methods. The wait is Cloudant's
+// `Retry-After` when present, exponential backoff otherwise. Other errors,
+// 4xx included, are returned right away, and so is the last one when
+// waiting would outlive the activation deadline. Every attempt takes a
+// token of the `MAX_RPS` limit first. When none is left the request fails
+// right away as if Cloudant had answered 429, see `rate_limited`.
+pub fn cloudant_call<F>(method: &str, call: F) -> Result<ureq::Response, ureq::Error>
+where F: FnMut() -> Result<ureq::Response, ureq::Error> {
+    call_retrying(method, matches!(method, "GET" | "HEAD" | "PUT" | "DELETE"), call)
+}
+
+// `cloudant_call` of a create-only PUT (a document id without `_rev`),
+// retried on 5xx like a POST: if the first attempt was applied, the retry
+// would get a 409 for the document this call created
+pub fn cloudant_create<F>(call: F) -> Result<ureq::Response, ureq::Error>
+where F: FnMut() -> Result<ureq::Response, ureq::Error> {
+    call_retrying("PUT", false, call)
+}
+
+fn call_retrying<F>(method: &str, idempotent: bool, mut call: F) -> Result<ureq::Response, ureq::Error>
+where F: FnMut() -> Result<ureq::Response, ureq::Error> {
+    let mut attempt = 0;
+    loop {
+        if !acquire() {
+            return Err(rate_limited())
+        }
+        match traced("cloudant", method, &mut call) {
+            Err(ureq::Error::Status(code, res)) if retryable(idempotent, code) && attempt < MAX_RETRIES => {
+                let delay = retry_delay(res.header("Retry-After"), attempt);
+                if remaining().is_some_and(|budget| budget <= delay) {
+                    return Err(ureq::Error::Status(code, res))
+                }
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result
+        }
+    }
+}
+
+// 429 and 503 tell the request was not applied, it can be sent again
+// whatever its method. Other 5xx may come after Cloudant committed a write,
+// so only idempotent requests are retried on them: a retried POST (or COPY,
+// or create-only PUT) could create a duplicate document, or get a
+// misleading 409.
+fn retryable(idempotent: bool, code: u16) -> bool {
+    match code {
+        429 | 503 => true,
+        500..=599 => idempotent,
+        _ => false
+    }
+}
+
+// A request refused by the `MAX_RPS` limit, shaped like Cloudant's own 429
+// so callers report it, next to what they did before it, as any other
+// failed request instead of the activation ending halfway
+fn rate_limited() -> ureq::Error {
+    let body = r#"{"error": "too_many_requests", "reason": "MAX_RPS exceeded for these credentials, retry later"}"#;
+    ureq::Error::Status(429, ureq::Response::new(429, "Too Many Requests", body).unwrap())
+}
+
+fn retry_delay(retry_after: Option<&str>, attempt: u32) -> Duration {
+    match retry_after.and_then(|secs| secs.trim().parse::<u64>().ok()) {
+        Some(secs) => Duration::from_secs(secs.min(RETRY_AFTER_MAX_SECS)),
+        None => Duration::from_millis(RETRY_BASE_MS << attempt)
+    }
+}
+
+// Current rev of a document, read from the `ETag` of a HEAD `request` for
+// it, e.g. to hand it to a client whose write was rejected with a 409.
+// `None` when the document is gone or Cloudant can't be reached.
+pub fn current_rev(request: ureq::Request) -> Option<String> {
+    let res = traced("cloudant", "HEAD", || request.call()).ok()?;
+    res.header("ETag").map(etag_rev)
+}
+
+// A failed Cloudant request, described for the response body
+#[derive(Debug)]
+pub struct CloudantError {
+    pub msg: String,
+    // HTTP status, when Cloudant answered at all
+    pub status: Option<u16>,
+    // Cloudant's own error body, e.g. {"error": "unauthorized", "reason": "..."}
+    pub body: Option<sj::Value>
+}
+
+// Turn a ureq error into a `CloudantError`. For non-2xx answers the raw
+// body is read, so Cloudant's actual error JSON reaches the caller instead
+// of an opaque status line.
+pub fn cloudant_error(err: ureq::Error) -> CloudantError {
+    match err {
+        ureq::Error::Status(code, res) => {
+            let raw = res.into_string().unwrap_or_default();
+            let body = sj::from_str::<sj::Value>(&raw)
+                .unwrap_or(sj::Value::String(raw));
+            let reason = match (body.get("error"), body.get("reason")) {
+                (Some(error), Some(reason)) => format!(
+                    ", {}: {}",
+                    error.as_str().unwrap_or_default(),
+                    reason.as_str().unwrap_or_default()
+                ),
+                _ => String::new()
+            };
+            CloudantError {
+                msg: format!("Failure querying Cloudant: status {}{}", code, reason),
+                status: Some(code),
+                body: Some(body)
+            }
+        },
+        err => CloudantError {
+            msg: format!("Failure querying Cloudant: {}", err),
+            status: None,
+            body: None
+        }
+    }
+}
+
+// How much of an unexpected Cloudant body is quoted in error messages
+const BODY_EXCERPT_BYTES: usize = 256;
+
+// Deserialize a successful Cloudant response. The raw body is read first,
+// so when it doesn't match `T` the error names Cloudant, the HTTP status
+// and what Cloudant actually returned (its first `BODY_EXCERPT_BYTES`).
+pub fn read_cloudant_json<T: DeserializeOwned>(res: ureq::Response) -> Result<T, String> {
+    let status = res.status();
+    let mut raw = String::new();
+    if let Err(err) = res.into_reader().read_to_string(&mut raw) {
+        return Err(format!("Failure reading Cloudant response (status {}): {}", status, err))
+    }
+    sj::from_str(&raw).map_err(|err| format!(
+        "Failure deserializing Cloudant response (status {}): {}; body: {}",
+        status, err, excerpt(&raw)
+    ))
+}
+
+fn excerpt(raw: &str) -> &str {
+    let mut end = raw.len().min(BODY_EXCERPT_BYTES);
+    while !raw.is_char_boundary(end) {
+        end -= 1;
+    }
+    &raw[..end]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    #[test]
+    fn rewrites_public_hostname() {
+        assert_eq!(
+            private_endpoint("https://acct.cloudantnosqldb.appdomain.cloud"),
+            "https://acct.private.cloudantnosqldb.appdomain.cloud"
+        );
+        assert_eq!(
+            private_endpoint("https://acct.cloudant.com:443/path"),
+            "https://acct.private.cloudant.com:443/path"
+        );
+    }
+
+    #[test]
+    fn keeps_private_hostname() {
+        let url = "https://acct.private.cloudant.com";
+        assert_eq!(private_endpoint(url), url);
+        assert_eq!(private_endpoint("http://localhost:5984"), "http://localhost:5984");
+    }
+
+    #[test]
+    fn joins_url_segments() {
+        assert_eq!(join_url("https://acct.cloudant.com", "todos"), "https://acct.cloudant.com/todos");
+        assert_eq!(join_url("https://acct.cloudant.com/", "todos"), "https://acct.cloudant.com/todos");
+        assert_eq!(join_url("https://acct.cloudant.com//", "/todos/_find"), "https://acct.cloudant.com/todos/_find");
+        assert_eq!(join_url("https://acct.cloudant.com", "/_replicate"), "https://acct.cloudant.com/_replicate");
+        assert_eq!(doc_url("https://acct.cloudant.com/", "todos", "abc-1"), "https://acct.cloudant.com/todos/abc-1");
+    }
+
+    #[test]
+    fn encodes_document_ids() {
+        let db = "https://acct.cloudant.com";
+        assert_eq!(doc_url(db, "todos", "abc-1"), "https://acct.cloudant.com/todos/abc-1");
+        assert_eq!(doc_url(db, "todos", "a/b c"), "https://acct.cloudant.com/todos/a%2Fb%20c");
+        assert_eq!(doc_url(db, "todos", "_design/app"), "https://acct.cloudant.com/todos/_design/app");
+        assert_eq!(
+            attachment_url(db, "todos", "abc-1", "notes v1.txt"),
+            "https://acct.cloudant.com/todos/abc-1/notes%20v1.txt"
+        );
+    }
+
+    #[test]
+    fn keeps_names_in_their_segment() {
+        assert_eq!(path_segment("by-date").unwrap(), "by-date");
+        assert_eq!(path_segment("../../otherdb/_design/x").unwrap(), "..%2F..%2Fotherdb%2F_design%2Fx");
+        assert_eq!(path_segment("%2E%2E").unwrap(), "%252E%252E");
+        assert!(path_segment("..").is_err());
+        assert!(path_segment(".").is_err());
+        let uri = join_url("https://acct.cloudant.com", &format!("todos/_design/{}/_view/x", path_segment("../../otherdb").unwrap()));
+        assert_eq!(url::Url::parse(&uri).unwrap().path(), "/todos/_design/..%2F..%2Fotherdb/_view/x");
+    }
+
+    #[test]
+    fn strips_etag_quotes() {
+        assert_eq!(etag_rev("\"1-967a00dff5e02add41819138abb3284d\""), "1-967a00dff5e02add41819138abb3284d");
+        assert_eq!(etag_rev("1-967a"), "1-967a");
+    }
+
+    #[test]
+    fn captures_cloudant_error_body() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/todos/_all_docs")
+            .with_status(401)
+            .with_body(r#"{"error": "unauthorized", "reason": "one of _all_docs, _design is required"}"#)
+            .create();
+
+        let err = ureq::get(&format!("{}/todos/_all_docs", server.url())).call().unwrap_err();
+        let err = cloudant_error(err);
+        assert_eq!(err.msg, "Failure querying Cloudant: status 401, unauthorized: one of _all_docs, _design is required");
+        assert_eq!(err.status, Some(401));
+        assert_eq!(err.body.unwrap()["error"], "unauthorized");
+    }
+
+    #[test]
+    fn keeps_non_json_error_body() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/").with_status(502).with_body("Bad Gateway").create();
+
+        let err = cloudant_error(ureq::get(&server.url()).call().unwrap_err());
+        assert_eq!(err.msg, "Failure querying Cloudant: status 502");
+        assert_eq!(err.body, Some(sj::Value::String("Bad Gateway".to_string())));
+    }
+
+    #[test]
+    fn labels_server_errors_as_cloudant() {
+        let mut server = mockito::Server::new();
+        server.mock("POST", "/todos")
+            .with_status(500)
+            .with_body(r#"{"error": "unknown_error", "reason": "function_clause"}"#)
+            .create();
+
+        let err = cloudant_error(ureq::post(&format!("{}/todos", server.url())).call().unwrap_err());
+        assert!(err.msg.contains("Cloudant") && err.msg.contains("500"), "{}", err.msg);
+        assert!(!err.msg.contains("IAM"), "{}", err.msg);
+    }
+
+    #[test]
+    fn quotes_unexpected_body() {
+        let mut server = mockito::Server::new();
+        let body = format!("<html>{}</html>", "x".repeat(1000));
+        server.mock("GET", "/todos").with_status(200).with_body(&body).create();
+
+        let res = ureq::get(&format!("{}/todos", server.url())).call().unwrap();
+        let err = read_cloudant_json::<sj::Value>(res).unwrap_err();
+        assert!(err.starts_with("Failure deserializing Cloudant response (status 200)"), "{}", err);
+        assert!(err.ends_with(&body[..BODY_EXCERPT_BYTES]), "{}", err);
+        assert!(!err.contains("IAM"), "{}", err);
+    }
+
+    #[test]
+    fn overrides_database_from_query() {
+        assert_eq!(resolve_database("todos", "id=1"), Ok("todos".to_string()));
+        assert_eq!(resolve_database("todos", "db=tenant_a"), Ok("tenant_a".to_string()));
+        assert_eq!(resolve_database("todos", "db=team%2Fa"), Ok("team%2Fa".to_string()));
+    }
+
+    #[test]
+    fn rejects_invalid_database_names() {
+        for name in &["Tenant", "1tenant", "_users", "", "ten%20ant", "a.b"] {
+            let query = format!("db={}", name);
+            assert!(resolve_database("todos", &query).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn payload_flag_controls_rewrite() {
+        let url = "https://acct.cloudant.com";
+        assert_eq!(resolve_db_url(url, Some(false)), url);
+        assert_eq!(resolve_db_url(url, Some(true)), "https://acct.private.cloudant.com");
+    }
+
+    #[test]
+    fn retries_rate_limited_requests() {
+        let mut server = mockito::Server::new();
+        let limited = server.mock("GET", "/todos")
+            .with_status(429)
+            .with_header("Retry-After", "0")
+            .with_body(r#"{"error": "too_many_requests", "reason": "You've exceeded your rate limit allowance."}"#)
+            .expect(2)
+            .create();
+        let ok = server.mock("GET", "/todos")
+            .with_status(200)
+            .with_body(r#"{"db_name": "todos"}"#)
+            .create();
+
+        let uri = format!("{}/todos", server.url());
+        let res = cloudant_call("GET", || ureq::get(&uri).call()).unwrap();
+        assert_eq!(res.status(), 200);
+        limited.assert();
+        ok.assert();
+    }
+
+    #[test]
+    fn retries_writes_only_when_not_applied() {
+        let mut server = mockito::Server::new();
+        let failing = server.mock("POST", "/todos")
+            .with_status(500)
+            .with_body(r#"{"error": "unknown_error", "reason": "timeout"}"#)
+            .expect(1)
+            .create();
+
+        let uri = format!("{}/todos", server.url());
+        assert!(matches!(cloudant_call("POST", || ureq::post(&uri).call()), Err(ureq::Error::Status(500, _))));
+        failing.assert();
+
+        let created = server.mock("PUT", "/todos/abc")
+            .with_status(502)
+            .expect(1)
+            .create();
+        let uri = format!("{}/todos/abc", server.url());
+        assert!(matches!(cloudant_create(|| ureq::put(&uri).call()), Err(ureq::Error::Status(502, _))));
+        created.assert();
+
+        assert!(retryable(false, 429) && retryable(false, 503));
+        assert!(!retryable(false, 502) && !retryable(false, 500));
+        assert!(retryable(true, 500) && retryable(true, 502) && retryable(true, 504));
+        assert!(!retryable(true, 404));
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let mut server = mockito::Server::new();
+        let missing = server.mock("GET", "/todos/missing")
+            .with_status(404)
+            .with_body(r#"{"error": "not_found", "reason": "missing"}"#)
+            .expect(1)
+            .create();
+
+        let uri = format!("{}/todos/missing", server.url());
+        assert!(matches!(cloudant_call("GET", || ureq::get(&uri).call()), Err(ureq::Error::Status(404, _))));
+        missing.assert();
+    }
+
+    #[test]
+    fn reports_rate_limit_as_429() {
+        let err = cloudant_error(rate_limited());
+        assert_eq!(err.status, Some(429));
+        assert_eq!(err.body.unwrap()["error"], "too_many_requests");
+        assert_eq!(ErrorCode::from_status(err.status), ErrorCode::RateLimited);
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(retry_delay(None, 0), Duration::from_millis(100));
+        assert_eq!(retry_delay(None, 2), Duration::from_millis(400));
+        assert_eq!(retry_delay(Some("2"), 0), Duration::from_secs(2));
+        assert_eq!(retry_delay(Some("3600"), 0), Duration::from_secs(RETRY_AFTER_MAX_SECS));
+    }
+
+    #[test]
+    fn reads_current_rev_from_etag() {
+        let mut server = mockito::Server::new();
+        server.mock("HEAD", "/todos/exampleid")
+            .with_status(200)
+            .with_header("ETag", "\"3-917fa2381192822767f010b95b45325b\"")
+            .create();
+        server.mock("HEAD", "/todos/missing")
+            .with_status(404)
+            .create();
+
+        let uri = format!("{}/todos/exampleid", server.url());
+        assert_eq!(current_rev(ureq::head(&uri)), Some("3-917fa2381192822767f010b95b45325b".to_string()));
+        let uri = format!("{}/todos/missing", server.url());
+        assert_eq!(current_rev(ureq::head(&uri)), None);
+    }
+}
@@ -0,0 +1,347 @@
+use std::{env, fmt};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{self as sj};
+use url::Url;
+use crate::cloudant::{resolve_database, resolve_db_url};
+use crate::deadline::within_deadline;
+use crate::error::ErrorCode;
+use crate::http::{agent, cloudant_headers};
+use crate::iam::{auth_mode, authenticate, iam_endpoint, AuthMode};
+use crate::rate_limit::set_key;
+
+/* Example Raw HTTP payload from IBM Cloud Functions:
+{
+    "<custom_params>": <value>,
+    ...
+    "__ow_method": "post",
+    "__ow_query": "name=Jane",
+    "__ow_body": "eyJuYW1lIjoiSmFuZSJ9",
+    "__ow_headers": {
+    "accept": "*\/\*",
+    "connection": "close",
+    "content-length": "15",
+    "content-type": "application/json",
+    "host": "172.17.0.1",
+    "user-agent": "curl/7.43.0"
+    },
+    "__ow_path": "",
+    "forward_headers": {"If-None-Match": "\"1-967a00dff5e02add41819138abb3284d\""}
+}
+`iam_apikey`, `db_url` and `database` may be left out of the payload and
+set through the environment instead, which then wins over the payload, see
+`Config::from_input`. So may the `username` and `password` used with
+`AUTH_MODE=basic`. The optional
+`forward_headers` are set on Cloudant requests, see `FORWARDED_REQUEST_HEADERS`. */
+#[derive(Deserialize)]
+pub struct ICFRawInput {
+    pub iam_apikey: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub db_url: Option<String>,
+    pub database: Option<String>,
+    pub use_private_endpoint: Option<bool>,
+    pub forward_headers: Option<sj::Map<String, sj::Value>>,
+    pub __ow_body: String,
+    pub __ow_headers: sj::Value,
+    pub __ow_method: String,
+    pub __ow_path: String,
+    pub __ow_query: String,
+    // Function-specific parameters, e.g. insert's `id_fields`
+    #[serde(flatten)]
+    pub params: sj::Map<String, sj::Value>
+}
+
+// Failure reading the function's input, see `read_input`
+#[derive(Debug)]
+pub enum InputError {
+    MissingArgument,
+    Parse(sj::Error)
+}
+
+impl InputError {
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::ParseError
+    }
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputError::MissingArgument => write!(f, "Missing raw HTTP request: expected it as the first argument"),
+            InputError::Parse(err) => write!(f, "Failure parsing raw HTTP request: {}", err)
+        }
+    }
+}
+
+// Deserialize the raw HTTP request OpenWhisk passes as the first argument
+// into the input struct of the calling function, usually `ICFRawInput`
+pub fn read_input<T: DeserializeOwned>() -> Result<T, InputError> {
+    parse_input(env::args().nth(1))
+}
+
+fn parse_input<T: DeserializeOwned>(arg: Option<String>) -> Result<T, InputError> {
+    let arg = arg.ok_or(InputError::MissingArgument)?;
+    sj::from_str(&arg).map_err(InputError::Parse)
+}
+
+// Connection settings of a function, resolved once at the top of `main`
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub iam_apikey: Option<String>,
+    // Basic auth credentials, only used with `AUTH_MODE=basic`
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Cloudant URL without trailing `/`, private endpoint already applied
+    pub db_url: String,
+    // Database name ready to be used as a URL path segment
+    pub database: String,
+    // Client headers to set on every Cloudant request
+    pub headers: Vec<(String, String)>
+}
+
+// Client headers that may be forwarded to Cloudant, e.g. for conditional
+// requests. Anything else (`Authorization` in particular) is rejected.
+pub const FORWARDED_REQUEST_HEADERS: [&str; 2] = ["If-None-Match", "If-Match"];
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    MissingDbUrl,
+    InvalidDbUrl(String),
+    MissingDatabase,
+    InvalidDatabase(String),
+    InvalidHeader(String)
+}
+
+impl ConfigError {
+    // Settings are the deployer's business, except for the `db` query param
+    // and `forward_headers` that come from the client
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ConfigError::InvalidDatabase(_) | ConfigError::InvalidHeader(_) => ErrorCode::BadRequest,
+            _ => ErrorCode::ConfigError
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::MissingDbUrl => write!(f, "Missing db_url: set it in the payload or the DB_URL env var"),
+            ConfigError::InvalidDbUrl(msg) => write!(f, "Invalid db_url: {}", msg),
+            ConfigError::MissingDatabase => write!(f, "Missing database: set it in the payload or the DATABASE env var"),
+            ConfigError::InvalidDatabase(msg) => write!(f, "{}", msg),
+            ConfigError::InvalidHeader(msg) => write!(f, "Invalid forward_headers: {}", msg)
+        }
+    }
+}
+
+impl Config {
+    // Payload fields, overridden by the `IAM_APIKEY`, `DB_USERNAME`,
+    // `DB_PASSWORD`, `DB_URL` and `DATABASE` env vars: a web action caller
+    // can't point the deployment's credentials at another host. The URL
+    // must be an absolute http(s) URL, and the database can be overridden
+    // by the `db` query param, see `resolve_database`.
+    pub fn from_input(input: &ICFRawInput) -> Result<Config, ConfigError> {
+        let database = env_or_payload(&input.database, "DATABASE")
+            .ok_or(ConfigError::MissingDatabase)?;
+        Config::build(input, database)
+    }
+
+    // Same as `from_input` for server-level functions (`_all_dbs`,
+    // `_replicate`, ...) that don't target a database: `database` is then
+    // left empty unless one is configured anyway
+    pub fn server_from_input(input: &ICFRawInput) -> Result<Config, ConfigError> {
+        let database = env_or_payload(&input.database, "DATABASE").unwrap_or_default();
+        Config::build(input, database)
+    }
+
+    fn build(input: &ICFRawInput, database: String) -> Result<Config, ConfigError> {
+        let iam_apikey = iam_apikey(input);
+        let username = env_or_payload(&input.username, "DB_USERNAME");
+        let password = env_or_payload(&input.password, "DB_PASSWORD");
+        let db_url = env_or_payload(&input.db_url, "DB_URL")
+            .ok_or(ConfigError::MissingDbUrl)?;
+
+        let parsed = Url::parse(&db_url)
+            .map_err(|err| ConfigError::InvalidDbUrl(format!("{}: {}", db_url, err)))?;
+        if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+            return Err(ConfigError::InvalidDbUrl(format!("{}: expected an http(s) URL", db_url)))
+        }
+        let db_url = resolve_db_url(db_url.trim_end_matches('/'), input.use_private_endpoint);
+        let database = resolve_database(&database, &input.__ow_query)
+            .map_err(ConfigError::InvalidDatabase)?;
+
+        let headers = forwarded_headers(input.forward_headers.as_ref())?;
+
+        // `MAX_RPS` is enforced per credential, see `rate_limit`
+        if let Some(credential) = iam_apikey.as_ref().or(username.as_ref()) {
+            set_key(credential);
+        }
+
+        Ok(Config { iam_apikey, username, password, db_url, database, headers })
+    }
+
+    // `Authorization` header value for Cloudant requests: a bearer IAM token,
+    // or with `AUTH_MODE=basic` the CouchDB credentials, skipping IAM
+    pub fn authorization(&self) -> Result<String, String> {
+        if auth_mode()? != AuthMode::Basic {
+            let token = authenticate(&iam_endpoint()?, self.iam_apikey.as_deref())?;
+            return Ok(format!("Bearer {}", token))
+        }
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => Ok(basic_auth(username, password)),
+            _ => Err("Missing username or password for AUTH_MODE=basic".to_string())
+        }
+    }
+
+    // Start a Cloudant request carrying the default `cloudant_headers` and
+    // the forwarded client headers, timing out before the activation deadline
+    pub fn request(&self, method: &str, uri: &str) -> ureq::Request {
+        let request = cloudant_headers().iter()
+            .fold(within_deadline(agent().request(method, uri), 1), |req, (name, value)| req.set(name, value));
+        self.headers.iter()
+            .fold(request, |req, (name, value)| req.set(name, value))
+    }
+}
+
+fn forwarded_headers(headers: Option<&sj::Map<String, sj::Value>>) -> Result<Vec<(String, String)>, ConfigError> {
+    headers.into_iter().flatten()
+        .map(|(name, value)| {
+            let allowed = FORWARDED_REQUEST_HEADERS.iter()
+                .find(|allowed| allowed.eq_ignore_ascii_case(name))
+                .ok_or_else(|| ConfigError::InvalidHeader(format!(
+                    "{} is not allowed, expected one of {}", name, FORWARDED_REQUEST_HEADERS.join(", ")
+                )))?;
+            match value.as_str() {
+                Some(value) => Ok((allowed.to_string(), value.to_string())),
+                None => Err(ConfigError::InvalidHeader(format!("{} must be a string", name)))
+            }
+        })
+        .collect()
+}
+
+// Admin operations (compaction, ...) are refused unless `ADMIN_ENABLED`
+// is `true` (or `1`) in the function's environment
+pub fn admin_enabled() -> bool {
+    matches!(env::var("ADMIN_ENABLED").as_deref(), Ok("true") | Ok("1"))
+}
+
+fn basic_auth(username: &str, password: &str) -> String {
+    format!("Basic {}", base64::encode(format!("{}:{}", username, password)))
+}
+
+// The apikey alone, for functions that only talk to IAM
+pub fn iam_apikey(input: &ICFRawInput) -> Option<String> {
+    env_or_payload(&input.iam_apikey, "IAM_APIKEY")
+}
+
+// The env var when set, the payload field otherwise. Blank values count as
+// missing, so an empty env var falls back to the payload.
+fn env_or_payload(value: &Option<String>, var: &str) -> Option<String> {
+    let present = |value: &String| !value.trim().is_empty();
+    env::var(var).ok().filter(present)
+        .or_else(|| value.clone().filter(present))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(db_url: &str, database: &str, query: &str) -> ICFRawInput {
+        ICFRawInput {
+            iam_apikey: Some("apikey".to_string()),
+            username: None,
+            password: None,
+            db_url: Some(db_url.to_string()),
+            database: Some(database.to_string()),
+            use_private_endpoint: Some(false),
+            forward_headers: None,
+            __ow_body: String::new(),
+            __ow_headers: sj::json!({}),
+            __ow_method: "get".to_string(),
+            __ow_path: String::new(),
+            __ow_query: query.to_string(),
+            params: sj::Map::new()
+        }
+    }
+
+    #[test]
+    fn builds_config_from_payload() {
+        let config = Config::from_input(&input("https://acct.cloudant.com/", "todos", "db=team/a"));
+        assert_eq!(config, Ok(Config {
+            iam_apikey: Some("apikey".to_string()),
+            username: None,
+            password: None,
+            db_url: "https://acct.cloudant.com".to_string(),
+            database: "team%2Fa".to_string(),
+            headers: Vec::new()
+        }));
+    }
+
+    #[test]
+    fn env_overrides_payload() {
+        let payload = Some("https://attacker.example".to_string());
+        env::remove_var("FN_TEST_ENV_OVERRIDE");
+        assert_eq!(env_or_payload(&payload, "FN_TEST_ENV_OVERRIDE"), payload);
+        env::set_var("FN_TEST_ENV_OVERRIDE", "https://acct.cloudant.com");
+        assert_eq!(env_or_payload(&payload, "FN_TEST_ENV_OVERRIDE").as_deref(), Some("https://acct.cloudant.com"));
+        env::set_var("FN_TEST_ENV_OVERRIDE", " ");
+        assert_eq!(env_or_payload(&payload, "FN_TEST_ENV_OVERRIDE"), payload);
+        env::remove_var("FN_TEST_ENV_OVERRIDE");
+    }
+
+    #[test]
+    fn server_config_needs_no_database() {
+        let mut server = input("https://acct.cloudant.com", "", "");
+        server.database = None;
+        assert_eq!(Config::server_from_input(&server).unwrap().database, "");
+    }
+
+    #[test]
+    fn rejects_invalid_db_url() {
+        assert!(matches!(
+            Config::from_input(&input("acct.cloudant.com", "todos", "")),
+            Err(ConfigError::InvalidDbUrl(_))
+        ));
+        assert!(matches!(
+            Config::from_input(&input("ftp://acct.cloudant.com", "todos", "")),
+            Err(ConfigError::InvalidDbUrl(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_database_override() {
+        assert!(matches!(
+            Config::from_input(&input("https://acct.cloudant.com", "todos", "db=Todos")),
+            Err(ConfigError::InvalidDatabase(_))
+        ));
+    }
+
+    #[test]
+    fn parses_input_argument() {
+        assert!(matches!(parse_input::<ICFRawInput>(None), Err(InputError::MissingArgument)));
+        assert!(matches!(parse_input::<ICFRawInput>(Some("{".to_string())), Err(InputError::Parse(_))));
+        let raw = r#"{"__ow_body": "", "__ow_headers": {}, "__ow_method": "get", "__ow_path": "", "__ow_query": "id=a", "id_fields": ["task"]}"#;
+        let input = parse_input::<ICFRawInput>(Some(raw.to_string())).unwrap();
+        assert_eq!(input.__ow_query, "id=a");
+        assert_eq!(input.params["id_fields"], sj::json!(["task"]));
+    }
+
+    #[test]
+    fn encodes_basic_auth() {
+        assert_eq!(basic_auth("admin", "pass"), "Basic YWRtaW46cGFzcw==");
+    }
+
+    #[test]
+    fn allowlists_forwarded_headers() {
+        let headers = sj::json!({"if-none-match": "\"1-abc\""});
+        assert_eq!(
+            forwarded_headers(headers.as_object()),
+            Ok(vec![("If-None-Match".to_string(), "\"1-abc\"".to_string())])
+        );
+        let headers = sj::json!({"Authorization": "Basic YWRtaW46cGFzcw=="});
+        assert!(matches!(forwarded_headers(headers.as_object()), Err(ConfigError::InvalidHeader(_))));
+        assert_eq!(forwarded_headers(None), Ok(Vec::new()));
+    }
+}
@@ -0,0 +1,47 @@
+use std::{env, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+// Time kept back from the activation deadline to print the error response
+// before OpenWhisk kills the process
+pub const DEADLINE_MARGIN_MS: u64 = 500;
+// Shortest timeout set on a request, ureq treats a zero timeout as none
+const MIN_TIMEOUT_MS: u64 = 1;
+
+// Time left before the activation deadline, minus `DEADLINE_MARGIN_MS`.
+// OpenWhisk injects the deadline as `__OW_DEADLINE` (epoch millis); `None`
+// when it doesn't, e.g. when running the binary by hand.
+pub fn remaining() -> Option<Duration> {
+    let deadline_ms = env::var("__OW_DEADLINE").ok()?.trim().parse::<u64>().ok()?;
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    Some(budget(deadline_ms, now_ms))
+}
+
+fn budget(deadline_ms: u64, now_ms: u64) -> Duration {
+    Duration::from_millis(deadline_ms.saturating_sub(DEADLINE_MARGIN_MS).saturating_sub(now_ms))
+}
+
+pub fn deadline_exceeded() -> bool {
+    remaining() == Some(Duration::ZERO)
+}
+
+// Clamp the timeout of `request` to its `1/share` of the remaining budget:
+// IAM gets half of it so the Cloudant request that follows still fits,
+// Cloudant gets all that is left
+pub fn within_deadline(request: ureq::Request, share: u32) -> ureq::Request {
+    match remaining() {
+        Some(budget) => request.timeout((budget / share).max(Duration::from_millis(MIN_TIMEOUT_MS))),
+        None => request
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_a_margin_before_the_deadline() {
+        assert_eq!(budget(10_000, 4_000), Duration::from_millis(5_500));
+        assert_eq!(budget(10_000, 9_600), Duration::ZERO);
+        assert_eq!(budget(10_000, 12_000), Duration::ZERO);
+    }
+}
@@ -0,0 +1,295 @@
+use std::{error, fmt};
+use serde::Serialize;
+use serde_json::{self as sj};
+use crate::body::BodyError;
+use crate::cloudant::{cloudant_error, CloudantError};
+use crate::config::{ConfigError, InputError};
+
+// Stable, machine-readable code of an error response, serialized as
+// `"code": "BAD_BASE64"` next to the human `msg` so clients can branch on
+// it without parsing prose
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    // Missing or invalid query params, unsupported method or content type
+    BadRequest,
+    MissingBody,
+    PayloadTooLarge,
+    BadBase64,
+    // Raw HTTP request or decoded body that isn't the expected JSON
+    ParseError,
+    // Well-formed document refused by the function's own checks
+    ValidationError,
+    // Missing or invalid settings: db_url, database, env vars
+    ConfigError,
+    // Missing or mismatched `X-Signature` while `HMAC_SECRET` is set
+    Unauthorized,
+    // Admin operation while `ADMIN_ENABLED` is off
+    Forbidden,
+    IamFailure,
+    CloudantError,
+    // IAM or Cloudant cut short by the activation deadline, see `deadline`
+    DeadlineExceeded,
+    NotFound,
+    Conflict,
+    // Create-only insert of an `_id` that is already taken
+    AlreadyExists,
+    // Cloudant still answering 429 after the retries, or the container's
+    // own outbound request budget (`MAX_RPS`) spent
+    RateLimited,
+    // Output over `MAX_RESPONSE_BYTES`, replaced by this error
+    ResponseTooLarge
+}
+
+impl ErrorCode {
+    // Code of a failed Cloudant request from its HTTP status
+    pub fn from_status(status: Option<u16>) -> ErrorCode {
+        match status {
+            Some(404) => ErrorCode::NotFound,
+            Some(409) => ErrorCode::Conflict,
+            Some(429) => ErrorCode::RateLimited,
+            _ => ErrorCode::CloudantError
+        }
+    }
+}
+
+// One failing field of a validation error, e.g. `{"field": "[1].task",
+// "message": "must not be empty"}`, so form UIs can flag every bad field
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String
+}
+
+impl FieldError {
+    pub fn new<F: Into<String>, M: Into<String>>(field: F, message: M) -> FieldError {
+        FieldError { field: field.into(), message: message.into() }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = sj::to_value(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", code.as_str().unwrap_or_default())
+    }
+}
+
+// Failure of a function, passed up from helpers with `?` and turned into
+// the error response once, at the top of `main` (see `respond_fn_err`)
+#[derive(Debug)]
+pub enum FnError {
+    // Raw HTTP request missing, or not the expected JSON
+    Input(InputError),
+    // Request body missing, too large or not decodable
+    Body(BodyError),
+    // Decoded body that isn't the expected JSON
+    Parse(sj::Error),
+    Base64(base64::DecodeError),
+    Config(ConfigError),
+    // Failure getting an `Authorization`, with IAM's message
+    Iam(String),
+    // Failed Cloudant request, with its status and error body
+    Cloudant(CloudantError),
+    // CORS preflight (`OPTIONS`) request, answered with the CORS headers
+    // alone rather than an error body, see `respond_preflight`
+    Preflight,
+    // Missing or mismatched `X-Signature`, answered with a real 401
+    Unauthorized(String),
+    // Error response with extra fields next to `code` and `msg`, sent with
+    // its own HTTP status, e.g. a failed health check's 503 or the results
+    // written before a bulk import failed
+    Detailed {
+        status: &'static str,
+        code: ErrorCode,
+        msg: String,
+        fields: sj::Map<String, sj::Value>
+    },
+    // Any other error response, e.g. `BadRequest` for an invalid param
+    Other(ErrorCode, String)
+}
+
+impl FnError {
+    pub fn new<S: Into<String>>(code: ErrorCode, msg: S) -> FnError {
+        FnError::Other(code, msg.into())
+    }
+
+    // Error response sent with `status`, the fields of the `fields` object
+    // added next to `code` and `msg`
+    pub fn detailed<S: Into<String>>(status: &'static str, code: ErrorCode, msg: S, fields: sj::Value) -> FnError {
+        let fields = match fields {
+            sj::Value::Object(fields) => fields,
+            _ => sj::Map::new()
+        };
+        FnError::Detailed { status, code, msg: msg.into(), fields }
+    }
+
+    // The same error with the fields of the `fields` object added, e.g. the
+    // documents written before a multi-request operation failed
+    pub fn with_fields(self, fields: sj::Value) -> FnError {
+        let (status, code, msg, mut all) = match self {
+            FnError::Detailed { status, code, msg, fields } => (status, code, msg, fields),
+            FnError::Cloudant(err) => {
+                let mut all = sj::Map::new();
+                all.insert("status".to_string(), sj::json!(err.status));
+                all.insert("cloudant_error".to_string(), sj::json!(err.body));
+                ("200 OK", ErrorCode::from_status(err.status), err.msg, all)
+            },
+            err => ("200 OK", err.code(), err.to_string(), sj::Map::new())
+        };
+        if let sj::Value::Object(fields) = fields {
+            all.extend(fields);
+        }
+        FnError::Detailed { status, code, msg, fields: all }
+    }
+
+    // Validation error listing every failing field in `errors`
+    pub fn validation<S: Into<String>>(msg: S, errors: Vec<FieldError>) -> FnError {
+        FnError::detailed("200 OK", ErrorCode::ValidationError, msg, sj::json!({"errors": errors}))
+    }
+
+    // Write rejected with a 409 conflict, carrying the document's
+    // `current_rev` so the client can retry without fetching it
+    pub fn conflict(err: ureq::Error, current_rev: Option<String>) -> FnError {
+        let err = cloudant_error(err);
+        FnError::detailed("200 OK", ErrorCode::Conflict, err.msg, sj::json!({
+            "status": err.status,
+            "cloudant_error": err.body,
+            "current_rev": current_rev
+        }))
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            FnError::Input(err) => err.code(),
+            FnError::Body(err) => err.code(),
+            FnError::Parse(_) => ErrorCode::ParseError,
+            FnError::Base64(_) => ErrorCode::BadBase64,
+            FnError::Config(err) => err.code(),
+            FnError::Iam(_) => ErrorCode::IamFailure,
+            FnError::Cloudant(err) => ErrorCode::from_status(err.status),
+            // Never sent: a preflight is answered without a body
+            FnError::Preflight => ErrorCode::BadRequest,
+            FnError::Unauthorized(_) => ErrorCode::Unauthorized,
+            FnError::Detailed { code, .. } | FnError::Other(code, _) => *code
+        }
+    }
+}
+
+impl fmt::Display for FnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FnError::Input(err) => write!(f, "{}", err),
+            FnError::Body(err) => write!(f, "{}", err),
+            FnError::Parse(err) => write!(f, "Failure deserializing decoded bytes: {}", err),
+            FnError::Base64(err) => write!(f, "Failure decoding base64 body: {}", err),
+            FnError::Config(err) => write!(f, "{}", err),
+            FnError::Preflight => write!(f, "CORS preflight request"),
+            FnError::Iam(msg) | FnError::Unauthorized(msg) | FnError::Other(_, msg) => write!(f, "{}", msg),
+            FnError::Detailed { msg, .. } => write!(f, "{}", msg),
+            FnError::Cloudant(err) => write!(f, "{}", err.msg)
+        }
+    }
+}
+
+impl error::Error for FnError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            FnError::Parse(err) => Some(err),
+            FnError::Base64(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<sj::Error> for FnError {
+    fn from(err: sj::Error) -> FnError {
+        FnError::Parse(err)
+    }
+}
+
+impl From<base64::DecodeError> for FnError {
+    fn from(err: base64::DecodeError) -> FnError {
+        FnError::Base64(err)
+    }
+}
+
+impl From<ureq::Error> for FnError {
+    fn from(err: ureq::Error) -> FnError {
+        FnError::Cloudant(cloudant_error(err))
+    }
+}
+
+impl From<InputError> for FnError {
+    fn from(err: InputError) -> FnError {
+        FnError::Input(err)
+    }
+}
+
+impl From<BodyError> for FnError {
+    fn from(err: BodyError) -> FnError {
+        FnError::Body(err)
+    }
+}
+
+impl From<ConfigError> for FnError {
+    fn from(err: ConfigError) -> FnError {
+        FnError::Config(err)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_screaming_snake_case() {
+        assert_eq!(sj::json!(ErrorCode::BadBase64), "BAD_BASE64");
+        assert_eq!(ErrorCode::IamFailure.to_string(), "IAM_FAILURE");
+        assert_eq!(ErrorCode::from_status(Some(404)), ErrorCode::NotFound);
+        assert_eq!(ErrorCode::from_status(None), ErrorCode::CloudantError);
+    }
+
+    #[test]
+    fn converts_into_fn_error() {
+        let err = FnError::from(sj::from_str::<sj::Value>("{").unwrap_err());
+        assert_eq!(err.code(), ErrorCode::ParseError);
+        assert!(err.to_string().starts_with("Failure deserializing decoded bytes"));
+        assert!(error::Error::source(&err).is_some());
+
+        let err = FnError::from(base64::decode("!").unwrap_err());
+        assert_eq!(err.code(), ErrorCode::BadBase64);
+
+        let res = ureq::Response::new(404, "Not Found", r#"{"error": "not_found", "reason": "missing"}"#).unwrap();
+        let err = FnError::from(ureq::Error::Status(404, res));
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert_eq!(err.to_string(), "Failure querying Cloudant: status 404, not_found: missing");
+
+        let err = FnError::from(ConfigError::MissingDbUrl);
+        assert_eq!(err.code(), ErrorCode::ConfigError);
+        assert_eq!(FnError::Other(ErrorCode::BadRequest, "Missing id".to_string()).to_string(), "Missing id");
+    }
+
+    #[test]
+    fn keeps_detailed_fields() {
+        let err = FnError::validation("1 invalid field", vec![FieldError::new("task", "must not be empty")]);
+        assert_eq!(err.code(), ErrorCode::ValidationError);
+        match err {
+            FnError::Detailed { status, fields, .. } => {
+                assert_eq!(status, "200 OK");
+                assert_eq!(fields["errors"][0]["field"], "task");
+            },
+            err => panic!("unexpected error: {:?}", err)
+        }
+
+        let res = ureq::Response::new(409, "Conflict", r#"{"error": "conflict", "reason": "Document update conflict."}"#).unwrap();
+        let err = FnError::conflict(ureq::Error::Status(409, res), Some("2-abc".to_string()));
+        assert_eq!(err.code(), ErrorCode::Conflict);
+        assert!(matches!(err, FnError::Detailed { ref fields, .. } if fields["current_rev"] == "2-abc" && fields["status"] == 409));
+
+        let res = ureq::Response::new(429, "Too Many Requests", r#"{"error": "too_many_requests", "reason": "slow down"}"#).unwrap();
+        let err = FnError::from(ureq::Error::Status(429, res)).with_fields(sj::json!({"deleted": 100}));
+        assert_eq!(err.code(), ErrorCode::RateLimited);
+        assert!(matches!(err, FnError::Detailed { ref fields, .. } if fields["deleted"] == 100 && fields["status"] == 429));
+    }
+}
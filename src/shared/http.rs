@@ -0,0 +1,182 @@
+use std::{env, io::Write, sync::OnceLock};
+use flate2::{Compression, write::GzEncoder};
+use serde_json::{self as sj};
+use crate::log::{correlation_id, warn};
+use crate::tls::{allow_invalid_certs, insecure_tls_config};
+
+// Request bodies at least this large are gzip-compressed before upload
+pub const GZIP_MIN_BYTES: usize = 64 * 1024;
+
+// HTTP agent shared by every IAM and Cloudant request of an activation
+static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+
+// The agent to build IAM and Cloudant requests from, instead of `ureq::get`
+// and friends. It tags every request with the activation's correlation id
+// as `X-Request-ID`, linking our logs with IBM's server-side ones.
+// Certificates are verified unless `ALLOW_INVALID_CERTS` is set.
+pub fn agent() -> &'static ureq::Agent {
+    AGENT.get_or_init(|| {
+        let mut builder = ureq::AgentBuilder::new()
+            .middleware(|request: ureq::Request, next: ureq::MiddlewareNext| match correlation_id() {
+                Some(id) => next.handle(request.set("X-Request-ID", id)),
+                None => next.handle(request)
+            });
+        if allow_invalid_certs() {
+            warn("ALLOW_INVALID_CERTS is set: TLS certificates are not verified");
+            builder = builder.tls_config(insecure_tls_config());
+        }
+        builder.build()
+    })
+}
+
+// Responses need no special handling: with its `gzip` feature enabled,
+// ureq sends `Accept-Encoding: gzip` on every request and transparently
+// decompresses gzip-encoded responses, so `into_json` sees plain JSON.
+
+// Headers set on every Cloudant request, see `Config::request`. Requests
+// may still override them, e.g. an attachment's own `Content-Type`. Both
+// default to `application/json`; `CLOUDANT_ACCEPT` and
+// `CLOUDANT_CONTENT_TYPE` change them for proxies that want other values.
+pub fn cloudant_headers() -> [(&'static str, String); 2] {
+    [
+        ("Accept", cloudant_header("CLOUDANT_ACCEPT")),
+        ("Content-Type", cloudant_header("CLOUDANT_CONTENT_TYPE"))
+    ]
+}
+
+fn cloudant_header(var: &str) -> String {
+    env::var(var).ok()
+        .filter(|value| !value.trim().is_empty())
+        .unwrap_or_else(|| "application/json".to_string())
+}
+
+// Send `body` as JSON, gzip-compressing it (`Content-Encoding: gzip`) when
+// the serialized document reaches `GZIP_MIN_BYTES`, e.g. for bulk inserts.
+pub fn send_json_body(request: ureq::Request, body: &sj::Value) -> Result<ureq::Response, ureq::Error> {
+    let bytes = sj::to_vec(body).unwrap();
+    let request = request.set("Content-Type", &cloudant_header("CLOUDANT_CONTENT_TYPE"));
+    if bytes.len() < GZIP_MIN_BYTES {
+        return request.send_bytes(&bytes)
+    }
+    request
+        .set("Content-Encoding", "gzip")
+        .send_bytes(&gzip(&bytes))
+}
+
+// Cloudant response headers copied into the web action's own `headers`,
+// e.g. so API Gateway or a CDN can cache on the document's `ETag`. The
+// comma-separated `FORWARD_HEADERS` env var overrides the default set.
+pub fn forward_headers() -> Vec<String> {
+    env::var("FORWARD_HEADERS")
+        .unwrap_or_else(|_| "ETag".to_string())
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// The `names` headers present on `res`, keyed by their configured spelling
+pub fn response_headers(res: &ureq::Response, names: &[String]) -> sj::Map<String, sj::Value> {
+    names.iter()
+        .filter_map(|name| res.header(name)
+            .map(|value| (name.clone(), sj::Value::String(value.to_string()))))
+        .collect()
+}
+
+fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // A multi-MB `_all_docs` response body
+    fn all_docs(rows: usize) -> Vec<u8> {
+        let rows: Vec<sj::Value> = (0..rows).map(|n| json!({
+            "id": format!("doc-{:08}", n),
+            "key": format!("doc-{:08}", n),
+            "value": {"rev": "1-967a00dff5e02add41819138abb3284d"}
+        })).collect();
+        sj::to_vec(&json!({"offset": 0, "rows": rows, "total_rows": rows.len()})).unwrap()
+    }
+
+    #[test]
+    fn decompresses_large_fetch() {
+        let raw = all_docs(40_000);
+        let compressed = gzip(&raw);
+        assert!(raw.len() > 2 * 1024 * 1024);
+        // Repetitive `_all_docs` rows compress to well under a tenth
+        assert!(compressed.len() * 10 < raw.len(),
+            "gzip reduced {} bytes to {}", raw.len(), compressed.len());
+
+        let mut server = mockito::Server::new();
+        let mock = server.mock("GET", "/todos/_all_docs")
+            .match_header("accept-encoding", mockito::Matcher::Regex("gzip".to_string()))
+            .with_header("content-encoding", "gzip")
+            .with_body(compressed)
+            .create();
+
+        let resp = ureq::get(&format!("{}/todos/_all_docs", server.url())).call().unwrap();
+        let data: sj::Value = resp.into_json().unwrap();
+        assert_eq!(data["rows"].as_array().unwrap().len(), 40_000);
+        mock.assert();
+    }
+
+    #[test]
+    fn compresses_large_bodies_only() {
+        let mut server = mockito::Server::new();
+        let small = server.mock("POST", "/small")
+            .match_header("content-encoding", mockito::Matcher::Missing)
+            .create();
+        let large = server.mock("POST", "/large")
+            .match_header("content-encoding", "gzip")
+            .match_header("content-type", "application/json")
+            .create();
+
+        let doc = json!({"task": "write docs", "done": false});
+        send_json_body(ureq::post(&format!("{}/small", server.url())), &doc).unwrap();
+        let docs = json!({"docs": vec![doc; 5_000]});
+        send_json_body(ureq::post(&format!("{}/large", server.url())), &docs).unwrap();
+        small.assert();
+        large.assert();
+    }
+
+    #[test]
+    fn defaults_cloudant_headers_to_json() {
+        let headers = cloudant_headers();
+        assert_eq!(headers[0], ("Accept", "application/json".to_string()));
+        assert_eq!(headers[1], ("Content-Type", "application/json".to_string()));
+    }
+
+    #[test]
+    fn copies_selected_response_headers() {
+        let res = ureq::Response::new(200, "OK", "{}").unwrap();
+        assert!(response_headers(&res, &["ETag".to_string()]).is_empty());
+
+        let res = "HTTP/1.1 200 OK\r\nETag: \"1-abc\"\r\nCache-Control: must-revalidate\r\nServer: CouchDB\r\n\r\n{}"
+            .parse::<ureq::Response>().unwrap();
+        let headers = response_headers(&res, &["ETag".to_string(), "Cache-Control".to_string()]);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["ETag"], "\"1-abc\"");
+        assert_eq!(headers["Cache-Control"], "must-revalidate");
+    }
+
+    #[test]
+    fn tags_requests_with_correlation_id() {
+        let id = crate::log::init_correlation_id(&json!({"x-request-id": "req-agent"}));
+        let mut server = mockito::Server::new();
+        let mock = server.mock("GET", "/todos")
+            .match_header("x-request-id", id)
+            .with_status(200)
+            .with_body("{}")
+            .create();
+
+        agent().get(&format!("{}/todos", server.url())).call().unwrap();
+        mock.assert();
+    }
+}
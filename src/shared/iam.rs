@@ -0,0 +1,455 @@
+use std::{env, fs, io::{self, Write}, path::{Path, PathBuf}};
+use ring::digest;
+use serde::{Serialize, Deserialize};
+use crate::cloudant::join_url;
+use crate::deadline::within_deadline;
+use crate::http::agent;
+use crate::log::traced;
+
+// Public IBM Cloud IAM endpoint, used when `IAM_ENDPOINT` is not set
+pub const IAM_URL: &str = "https://iam.cloud.ibm.com";
+
+/* Example HTTP response from IBM Cloud IAM:
+{
+    "access_token": "<omitted>",
+    "refresh_token": "not_supported",
+    "token_type": "Bearer",
+    "expires_in": 3600,
+    "expiration": 1616239535,
+    "scope": "ibm openid"
+} */
+#[derive(Deserialize, Serialize, Debug)]
+pub struct IAMResponse {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    pub expires_in: i32,
+    pub expiration: i32,
+    pub scope: String
+}
+
+// Resolve the IAM endpoint from the `IAM_ENDPOINT` env var, e.g.
+// `https://iam.test.cloud.ibm.com` for staging or a private endpoint
+// on IBM Cloud dedicated. Falls back to the public `IAM_URL`.
+pub fn iam_endpoint() -> Result<String, String> {
+    resolve_iam_endpoint(env::var("IAM_ENDPOINT").ok())
+}
+
+fn resolve_iam_endpoint(value: Option<String>) -> Result<String, String> {
+    let url = match value {
+        Some(url) => url.trim().to_string(),
+        None => return Ok(IAM_URL.to_string())
+    };
+    if url.is_empty() {
+        return Err("Invalid IAM_ENDPOINT: must not be empty".to_string())
+    }
+    if !url.starts_with("https://") {
+        return Err(format!("Invalid IAM_ENDPOINT: {} must start with https://", url))
+    }
+    Ok(url)
+}
+
+// Default path of the compute-resource token injected into workloads that
+// run with an associated trusted profile, overridable via `CR_TOKEN_FILE`
+pub const CR_TOKEN_FILE: &str = "/var/run/secrets/codeengine.cloud.ibm.com/compute-resource-token/token";
+
+// How the function authenticates, selected by `AUTH_MODE`
+#[derive(Debug, PartialEq)]
+pub enum AuthMode {
+    // `AUTH_MODE` unset, `apikey` or `iam`: the `iam_apikey` input parameter
+    ApiKey,
+    // `AUTH_MODE=cr_token`: a trusted profile's compute-resource token
+    CrToken,
+    // `AUTH_MODE=basic`: HTTP basic auth against vanilla CouchDB, no IAM
+    // involved, see `Config::authorization`
+    Basic
+}
+
+pub fn auth_mode() -> Result<AuthMode, String> {
+    match env::var("AUTH_MODE").as_deref() {
+        Err(_) | Ok("apikey") | Ok("iam") => Ok(AuthMode::ApiKey),
+        Ok("cr_token") => Ok(AuthMode::CrToken),
+        Ok("basic") => Ok(AuthMode::Basic),
+        Ok(other) => Err(format!("Invalid AUTH_MODE: {}", other))
+    }
+}
+
+// Get an IAM access token from `base_url` using the configured `AUTH_MODE`
+pub fn authenticate(base_url: &str, apikey: Option<&str>) -> Result<String, String> {
+    match auth_mode()? {
+        AuthMode::ApiKey => match apikey {
+            Some(apikey) => get_iam_token(base_url, apikey),
+            None => Err("Missing iam_apikey".to_string())
+        },
+        AuthMode::CrToken => {
+            let path = env::var("CR_TOKEN_FILE").unwrap_or_else(|_| CR_TOKEN_FILE.to_string());
+            let profile_id = env::var("IAM_PROFILE_ID")
+                .map_err(|_| "Missing IAM_PROFILE_ID for AUTH_MODE=cr_token".to_string())?;
+            get_cr_iam_token(base_url, &path, &profile_id)
+        },
+        AuthMode::Basic => Err("AUTH_MODE=basic does not authenticate against IAM".to_string())
+    }
+}
+
+// Exchange the compute-resource token stored at `path` for an IAM access
+// token of the trusted profile `profile_id`
+/* Reference request:
+    curl -X POST \
+        "https://iam.cloud.ibm.com/identity/token" \
+        --header 'Content-Type: application/x-www-form-urlencoded' \
+        --header 'Accept: application/json' \
+        --data-urlencode 'grant_type=urn:ibm:params:oauth:grant-type:cr-token' \
+        --data-urlencode 'cr_token={cr_token}' \
+        --data-urlencode 'profile_id={profile_id}'
+*/
+pub fn get_cr_iam_token(base_url: &str, path: &str, profile_id: &str) -> Result<String, String> {
+    let cr_token = match fs::read_to_string(path) {
+        Ok(token) => token.trim().to_string(),
+        Err(err) => return Err(format!("Failure reading compute-resource token: {}", err))
+    };
+    let uri = join_url(base_url, "identity/token");
+    let iam_data = request_token(&uri, &[
+        ("cr_token", &cr_token),
+        ("profile_id", profile_id),
+        ("grant_type", "urn:ibm:params:oauth:grant-type:cr-token")
+    ])?;
+    Ok(iam_data.access_token)
+}
+
+// Refresh tokens handed out by IAM are kept in the container's temp dir:
+// the action proxy runs the binary once per activation, so that is how the
+// next activation of a warm container gets them. One file per IAM endpoint
+// and apikey, named after their SHA-256 so the apikey never hits the disk.
+fn refresh_token_file(base_url: &str, apikey: &str) -> PathBuf {
+    env::temp_dir().join(format!("fn-iam-refresh-{}", sha256_hex(&format!("{}\n{}", base_url, apikey))))
+}
+
+// Hex SHA-256 of a secret, to name the files kept for it
+pub(crate) fn sha256_hex(secret: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, secret.as_bytes());
+    hash.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn load_refresh_token(path: &Path) -> Option<String> {
+    let token = fs::read_to_string(path).ok()?;
+    Some(token.trim().to_string()).filter(|token| !token.is_empty())
+}
+
+// Failing to save the refresh token only costs the next activation an
+// apikey grant, it doesn't fail this one
+fn save_refresh_token(path: &Path, token: Option<&str>) {
+    let _ = match token {
+        Some(token) => write_private(path, token),
+        None => fs::remove_file(path)
+    };
+}
+
+// The refresh token is a credential: readable by the function's user only
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+// Request an IAM access token for `apikey` from the IAM server at `base_url`.
+// When an earlier activation of this container got a usable refresh token
+// for the same apikey, it is exchanged first (`grant_type=refresh_token`)
+// so the apikey doesn't go over the wire again; if that fails, the regular
+// apikey grant is used. On failure, the returned `Err` holds the message
+// for the response body.
+/* Reference request:
+    curl -X POST \
+        "https://iam.cloud.ibm.com/identity/token" \
+        --header 'Content-Type: application/x-www-form-urlencoded' \
+        --header 'Accept: application/json' \
+        --data-urlencode 'grant_type=urn:ibm:params:oauth:grant-type:apikey' \
+        --data-urlencode 'apikey={api_key}'
+*/
+pub fn get_iam_token(base_url: &str, apikey: &str) -> Result<String, String> {
+    let uri = join_url(base_url, "identity/token");
+
+    let path = refresh_token_file(base_url, apikey);
+    let refresh_token = load_refresh_token(&path);
+    let refreshed = refresh_token.and_then(|token| request_token(&uri, &[
+        ("refresh_token", &token),
+        ("grant_type", "refresh_token")
+    ]).ok());
+
+    let iam_data = match refreshed {
+        Some(iam_data) => iam_data,
+        None => request_token(&uri, &[
+            ("apikey", apikey),
+            ("grant_type", "urn:ibm:params:oauth:grant-type:apikey")
+        ])?
+    };
+
+    // Remember the refresh token for the next invocation, unless IAM
+    // doesn't support refreshing for this grant
+    let supported = Some(iam_data.refresh_token.as_str()).filter(|token| *token != "not_supported");
+    save_refresh_token(&path, supported);
+    Ok(iam_data.access_token)
+}
+
+/* Example HTTP response from IBM Cloud IAM `/v1/apikeys/details`:
+{
+    "id": "ApiKey-5f7c3b1e-...",
+    "name": "functions-key",
+    "iam_id": "IBMid-270000ABCD",
+    "account_id": "0123456789abcdef0123456789abcdef",
+    "created_by": "IBMid-270000ABCD",
+    ...
+} */
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct APIKeyDetails {
+    pub id: String,
+    pub name: String,
+    pub iam_id: String,
+    pub account_id: String
+}
+
+// Look up the identity `apikey` belongs to, authenticated with `token`
+/* Reference request:
+    curl -X GET \
+        "https://iam.cloud.ibm.com/v1/apikeys/details" \
+        --header 'Authorization: Bearer {token}' \
+        --header 'IAM-ApiKey: {api_key}'
+*/
+pub fn get_apikey_details(base_url: &str, token: &str, apikey: &str) -> Result<APIKeyDetails, String> {
+    let uri = join_url(base_url, "v1/apikeys/details");
+    let request = within_deadline(agent().get(&uri), 2)
+        .set("Authorization", &format!("Bearer {}", token))
+        .set("IAM-ApiKey", apikey)
+        .set("Accept", "application/json");
+    let iam_resp = match traced("iam", "GET", || request.call()) {
+            Ok(iam_resp) => iam_resp,
+            Err(ureq::Error::Status(code, _)) => {
+                return Err(format!("Failure requesting IAM identity: status {}", code))
+            },
+            Err(_) => {
+                return Err("Failure requesting IAM identity".to_string())
+            }
+        };
+
+    // Deserialize IAM response
+    match iam_resp.into_json::<APIKeyDetails>() {
+        Ok(details) => Ok(details),
+        Err(err) => Err(format!("Failure deserializing IAM response: {}", err))
+    }
+}
+
+// POST a token grant to IAM and deserialize its response
+fn request_token(uri: &str, form: &[(&str, &str)]) -> Result<IAMResponse, String> {
+    let request = within_deadline(agent().post(uri), 2)
+        .set("Content-Type", "application/x-www-form-urlencoded")
+        .set("Accept", "application/json");
+    let iam_resp = match traced("iam", "POST", || request.send_form(form)) {
+            Ok(iam_resp) => iam_resp,
+            Err(ureq::Error::Status(code, _)) => {
+                // IAM answered, but rejected the request (e.g. 401 on a bad apikey)
+                return Err(format!("Failure requesting IAM token: status {}", code))
+            },
+            Err(_) => {
+                // Transport failure, IAM could not be reached
+                return Err("Failure requesting IAM token".to_string())
+            }
+        };
+
+    // Deserialize IAM response
+    match iam_resp.into_json::<IAMResponse>() {
+        Ok(iam_data) => Ok(iam_data),
+        Err(err) => Err(format!("Failure deserializing IAM response: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    #[test]
+    fn defaults_to_public_endpoint() {
+        assert_eq!(resolve_iam_endpoint(None), Ok(IAM_URL.to_string()));
+    }
+
+    #[test]
+    fn accepts_custom_endpoint() {
+        let url = "https://iam.test.cloud.ibm.com".to_string();
+        assert_eq!(resolve_iam_endpoint(Some(url.clone())), Ok(url));
+    }
+
+    #[test]
+    fn rejects_invalid_endpoint() {
+        assert!(resolve_iam_endpoint(Some("".to_string())).is_err());
+        assert!(resolve_iam_endpoint(Some("  ".to_string())).is_err());
+        assert!(resolve_iam_endpoint(Some("http://iam.cloud.ibm.com".to_string())).is_err());
+    }
+
+    #[test]
+    fn fetches_token() {
+        let mut server = mockito::Server::new();
+        let mock = server.mock("POST", "/identity/token")
+            .match_header("content-type", "application/x-www-form-urlencoded")
+            .with_status(200)
+            .with_body(r#"{
+                "access_token": "abc123",
+                "refresh_token": "not_supported",
+                "token_type": "Bearer",
+                "expires_in": 3600,
+                "expiration": 1616239535,
+                "scope": "ibm openid"
+            }"#)
+            .create();
+
+        assert_eq!(get_iam_token(&server.url(), "key-ok"), Ok("abc123".to_string()));
+        mock.assert();
+    }
+
+    #[test]
+    fn reports_unauthorized_apikey() {
+        let mut server = mockito::Server::new();
+        server.mock("POST", "/identity/token")
+            .with_status(401)
+            .with_body(r#"{"errorCode": "BXNIM0415E"}"#)
+            .create();
+
+        let err = get_iam_token(&server.url(), "bad-key").unwrap_err();
+        assert_eq!(err, "Failure requesting IAM token: status 401");
+    }
+
+    #[test]
+    fn reports_server_error() {
+        let mut server = mockito::Server::new();
+        server.mock("POST", "/identity/token")
+            .with_status(503)
+            .create();
+
+        let err = get_iam_token(&server.url(), "key-5xx").unwrap_err();
+        assert_eq!(err, "Failure requesting IAM token: status 503");
+    }
+
+    #[test]
+    fn reports_malformed_response() {
+        let mut server = mockito::Server::new();
+        server.mock("POST", "/identity/token")
+            .with_status(200)
+            .with_body(r#"{"access_token": 42}"#)
+            .create();
+
+        let err = get_iam_token(&server.url(), "key-malformed").unwrap_err();
+        assert!(err.starts_with("Failure deserializing IAM response"), "{}", err);
+    }
+
+    fn token_response(access_token: &str, refresh_token: &str) -> String {
+        format!(r#"{{
+            "access_token": "{}",
+            "refresh_token": "{}",
+            "token_type": "Bearer",
+            "expires_in": 3600,
+            "expiration": 1616239535,
+            "scope": "ibm openid"
+        }}"#, access_token, refresh_token)
+    }
+
+    #[test]
+    fn exchanges_refresh_token() {
+        let mut server = mockito::Server::new();
+        let apikey_grant = server.mock("POST", "/identity/token")
+            .match_body(Matcher::UrlEncoded("apikey".to_string(), "key-refresh".to_string()))
+            .with_body(token_response("first", "refresh-1"))
+            .expect(1)
+            .create();
+        let refresh_grant = server.mock("POST", "/identity/token")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("grant_type".to_string(), "refresh_token".to_string()),
+                Matcher::UrlEncoded("refresh_token".to_string(), "refresh-1".to_string())
+            ]))
+            .with_body(token_response("second", "refresh-2"))
+            .expect(1)
+            .create();
+
+        assert_eq!(get_iam_token(&server.url(), "key-refresh"), Ok("first".to_string()));
+        // The next activation is another process: the token comes from disk
+        let path = refresh_token_file(&server.url(), "key-refresh");
+        assert_eq!(load_refresh_token(&path), Some("refresh-1".to_string()));
+        assert!(!path.to_string_lossy().contains("key-refresh"));
+        assert_eq!(get_iam_token(&server.url(), "key-refresh"), Ok("second".to_string()));
+        assert_eq!(load_refresh_token(&path), Some("refresh-2".to_string()));
+        apikey_grant.assert();
+        refresh_grant.assert();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_apikey_when_refresh_fails() {
+        let mut server = mockito::Server::new();
+        let refresh_grant = server.mock("POST", "/identity/token")
+            .match_body(Matcher::UrlEncoded("grant_type".to_string(), "refresh_token".to_string()))
+            .with_status(400)
+            .expect(1)
+            .create();
+        let apikey_grant = server.mock("POST", "/identity/token")
+            .match_body(Matcher::UrlEncoded("apikey".to_string(), "key-fallback".to_string()))
+            .with_body(token_response("fresh", "refresh-1"))
+            .expect(2)
+            .create();
+
+        assert_eq!(get_iam_token(&server.url(), "key-fallback"), Ok("fresh".to_string()));
+        assert_eq!(get_iam_token(&server.url(), "key-fallback"), Ok("fresh".to_string()));
+        refresh_grant.assert();
+        apikey_grant.assert();
+        fs::remove_file(refresh_token_file(&server.url(), "key-fallback")).unwrap();
+    }
+
+    #[test]
+    fn exchanges_compute_resource_token() {
+        let path = env::temp_dir().join("fast-rust-cr-token");
+        fs::write(&path, "cr-token-value\n").unwrap();
+
+        let mut server = mockito::Server::new();
+        let mock = server.mock("POST", "/identity/token")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("grant_type".to_string(), "urn:ibm:params:oauth:grant-type:cr-token".to_string()),
+                Matcher::UrlEncoded("cr_token".to_string(), "cr-token-value".to_string()),
+                Matcher::UrlEncoded("profile_id".to_string(), "Profile-1234".to_string())
+            ]))
+            .with_body(token_response("profile-token", "not_supported"))
+            .create();
+
+        let token = get_cr_iam_token(&server.url(), path.to_str().unwrap(), "Profile-1234");
+        assert_eq!(token, Ok("profile-token".to_string()));
+        mock.assert();
+    }
+
+    #[test]
+    fn reports_missing_compute_resource_token() {
+        let err = get_cr_iam_token("https://iam.cloud.ibm.com", "/nonexistent/token", "Profile-1234").unwrap_err();
+        assert!(err.starts_with("Failure reading compute-resource token"), "{}", err);
+    }
+
+    #[test]
+    fn fetches_apikey_details() {
+        let mut server = mockito::Server::new();
+        let mock = server.mock("GET", "/v1/apikeys/details")
+            .match_header("authorization", "Bearer abc123")
+            .match_header("iam-apikey", "key-details")
+            .with_status(200)
+            .with_body(r#"{
+                "id": "ApiKey-1",
+                "name": "functions-key",
+                "iam_id": "IBMid-270000ABCD",
+                "account_id": "0123456789abcdef",
+                "created_by": "IBMid-270000ABCD"
+            }"#)
+            .create();
+
+        let details = get_apikey_details(&server.url(), "abc123", "key-details").unwrap();
+        assert_eq!(details.iam_id, "IBMid-270000ABCD");
+        assert_eq!(details.account_id, "0123456789abcdef");
+        mock.assert();
+    }
+}
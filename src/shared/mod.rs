@@ -0,0 +1,34 @@
+// Shared building blocks for the serverless functions in this crate.
+// Every binary under `src/` links against this library, so logic that
+// is common to all functions (IAM, Cloudant requests, ...) lives here.
+
+pub mod body;
+pub mod cloudant;
+pub mod config;
+pub mod deadline;
+pub mod error;
+pub mod http;
+pub mod iam;
+pub mod log;
+pub mod output;
+pub mod prelude;
+pub mod query;
+pub mod rate_limit;
+pub mod signature;
+pub mod stats;
+pub mod timing;
+pub mod tls;
+
+pub use body::{BodyError, content_type, decode_body, decode_optional_body, form_to_json};
+pub use cloudant::{attachment_url, cloudant_call, cloudant_create, cloudant_error, current_rev, doc_url, etag_rev, join_url, path_segment, read_cloudant_json, resolve_database, resolve_db_url, valid_database_name, CloudantError};
+pub use config::{admin_enabled, iam_apikey, read_input, Config, ConfigError, ICFRawInput, InputError};
+pub use deadline::{deadline_exceeded, remaining, within_deadline};
+pub use error::{ErrorCode, FieldError, FnError};
+pub use http::{agent, cloudant_headers, forward_headers, response_headers, send_json_body};
+pub use iam::{authenticate, get_apikey_details, get_iam_token, iam_endpoint, APIKeyDetails, IAMResponse, IAM_URL};
+pub use log::{init_correlation_id, log, log_level, traced, warn, LogLevel};
+pub use output::{API_VERSION, init_verbosity, respond_cloudant_err, respond_conflict, respond_err, respond_fn_err, respond_ok, respond_ok_streamed, respond_ok_with_headers, respond_preflight, respond_result, respond_status, respond_unauthorized, respond_validation_err};
+pub use prelude::{prelude, read_request, server_prelude};
+pub use query::{json_string_param, query_flag, query_param, query_params, write_quorum};
+pub use signature::verify_signature;
+pub use timing::{rfc3339, Timings};
@@ -0,0 +1,478 @@
+use std::{env, io::{self, BufWriter, Write}, process, sync::OnceLock};
+use serde_json::{self as sj, json};
+use crate::deadline::deadline_exceeded;
+use crate::error::{ErrorCode, FieldError, FnError};
+use crate::log::{init_correlation_id, log, LogLevel};
+use crate::query::{query_flag, query_param};
+use crate::stats;
+
+// Version of the response body shape, sent as `api_version` in every
+// success and error body. Bump it when the envelope changes so clients can
+// branch on it.
+pub const API_VERSION: &str = "1";
+
+// Whether successful responses keep their `err`/`msg` fields, set once by
+// `init_verbosity`; verbose when never set
+static VERBOSE: OnceLock<bool> = OnceLock::new();
+
+// Whether the output is pretty-printed, set once by `init_verbosity`
+static PRETTY: OnceLock<bool> = OnceLock::new();
+
+// Whether bodies carry the container's invocation counters, set once by
+// `init_verbosity` from `stats=true`, see `shared::stats`
+static STATS: OnceLock<bool> = OnceLock::new();
+
+// Pick the response verbosity of this activation: `verbose=false` in the
+// query string, or a `verbose=false` parameter of the `Accept` header (e.g.
+// `application/json; verbose=false`), asks for minimal responses holding
+// only the data. The query param wins over the header. Output formatting
+// is picked at the same time, see `pretty`, and so is `stats=true`.
+pub fn init_verbosity(headers: &sj::Value, query: &str) -> bool {
+    PRETTY.get_or_init(|| pretty(query));
+    STATS.get_or_init(|| query_flag(query, "stats"));
+    *VERBOSE.get_or_init(|| verbose(headers, query))
+}
+
+// Pretty-printed output for manual testing: `pretty=true` in the query
+// string or `PRETTY=1` (or `true`) in the environment. Compact otherwise.
+fn pretty(query: &str) -> bool {
+    query_param(query, "pretty").as_deref() == Some("true")
+        || matches!(env::var("PRETTY").as_deref(), Ok("1") | Ok("true"))
+}
+
+// Serialize the final output, pretty-printed when asked for. Errors raised
+// before `init_verbosity` only honor `PRETTY`.
+fn to_output_string(o: &sj::Value) -> String {
+    let pretty = PRETTY.get().copied().unwrap_or_else(|| pretty(""));
+    if pretty {
+        sj::to_string_pretty(o).unwrap()
+    } else {
+        sj::to_string(o).unwrap()
+    }
+}
+
+fn verbose(headers: &sj::Value, query: &str) -> bool {
+    if let Some(value) = query_param(query, "verbose") {
+        return value != "false"
+    }
+    let accept = headers.get("accept").and_then(|accept| accept.as_str()).unwrap_or_default();
+    !accept.split([',', ';'])
+        .any(|param| param.trim().eq_ignore_ascii_case("verbose=false"))
+}
+
+// CORS headers for browser clients, added to every response. The allowed
+// origin comes from the `CORS_ORIGIN` env var (default `*`).
+pub fn cors_headers() -> sj::Value {
+    let origin = env::var("CORS_ORIGIN").unwrap_or_else(|_| "*".to_string());
+    json!({
+        "Access-Control-Allow-Origin": origin,
+        "Access-Control-Allow-Methods": "GET, POST, PUT, DELETE, OPTIONS",
+        "Access-Control-Allow-Headers": "Content-Type, Authorization, X-Request-ID"
+    })
+}
+
+// Successful response: `body` wrapped in the web action envelope
+pub fn respond_ok(body: sj::Value) -> ! {
+    respond_ok_with_headers(body, sj::Map::new())
+}
+
+// Successful response with extra `headers` (e.g. forwarded from Cloudant)
+// next to the CORS ones
+pub fn respond_ok_with_headers(body: sj::Value, headers: sj::Map<String, sj::Value>) -> ! {
+    let mut all = cors_headers();
+    all.as_object_mut().unwrap().extend(headers);
+    emit("200 OK", all, Some(minimal(body)))
+}
+
+// Successful response whose `field` is written by `stream` instead of
+// being held in `body`, e.g. rows copied from a large Cloudant response one
+// at a time. `stream` returns extra fields to add next to `field` once it's
+// done (e.g. `truncated`). The output is held until complete, up to
+// `MAX_RESPONSE_BYTES`: past them `stream` is stopped and the response is
+// replaced with `RESPONSE_TOO_LARGE`, and a failing `stream` gets an error
+// response.
+pub fn respond_ok_streamed<F>(mut body: sj::Value, field: &str, stream: F) -> !
+where F: FnOnce(&mut dyn Write) -> Result<sj::Map<String, sj::Value>, String> {
+    body[field] = json!(STREAM_MARKER);
+    let o = output("200 OK", cors_headers(), Some(minimal(body)));
+    let max = max_response_bytes();
+    let mut out = Bounded { buf: Vec::new(), max, exceeded: false };
+    match write_streamed(&mut out, &o, stream) {
+        Ok(()) => finish(&out.buf, true),
+        Err(_) if out.exceeded => finish(format!("{}\n", too_large("the streamed response", max)).as_bytes(), false),
+        Err(msg) => respond_err(ErrorCode::CloudantError, msg)
+    }
+}
+
+// Output buffer refusing writes past `max` bytes
+struct Bounded {
+    buf: Vec<u8>,
+    max: usize,
+    exceeded: bool
+}
+
+impl Write for Bounded {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.max {
+            self.exceeded = true;
+            return Err(io::Error::other(format!("response over {} bytes", self.max)))
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Placeholder of the streamed field in the serialized response
+const STREAM_MARKER: &str = "\u{0}stream\u{0}";
+
+fn write_streamed<F>(out: &mut dyn Write, o: &sj::Value, stream: F) -> Result<(), String>
+where F: FnOnce(&mut dyn Write) -> Result<sj::Map<String, sj::Value>, String> {
+    let serialized = to_output_string(o);
+    let marker = sj::to_string(STREAM_MARKER).unwrap();
+    let (prefix, suffix) = serialized.split_once(&marker).unwrap();
+    out.write_all(prefix.as_bytes()).map_err(|err| err.to_string())?;
+    let extra = stream(&mut *out)?;
+    for (name, value) in extra {
+        write!(out, ",{}:{}", sj::to_string(&name).unwrap(), value).map_err(|err| err.to_string())?;
+    }
+    writeln!(out, "{}", suffix).map_err(|err| err.to_string())?;
+    out.flush().map_err(|err| err.to_string())
+}
+
+// Drop `err`/`msg` from a successful body when minimal responses were asked
+fn minimal(mut body: sj::Value) -> sj::Value {
+    if !VERBOSE.get().copied().unwrap_or(true) {
+        if let Some(fields) = body.as_object_mut() {
+            fields.remove("err");
+            fields.remove("msg");
+        }
+    }
+    body
+}
+
+// Print the outcome of a function's `run` once: its successful body, or
+// the error response of the `FnError` it failed with. Both exit with a
+// success code, OpenWhisk reads the outcome from the output.
+pub fn respond_result(result: Result<sj::Value, FnError>) -> ! {
+    match result {
+        Ok(body) => respond_ok(body),
+        Err(err) => respond_fn_err(err)
+    }
+}
+
+// Response with an explicit HTTP status, e.g. `503 Service Unavailable`
+// for a failed health check
+pub fn respond_status(status: &str, body: sj::Value) -> ! {
+    emit(status, cors_headers(), Some(body))
+}
+
+// Error response: `{"err": true, "code": code, "msg": msg}` in the web
+// action envelope
+pub fn respond_err<S: Into<String>>(code: ErrorCode, msg: S) -> ! {
+    let (code, msg) = past_deadline(code, msg.into());
+    emit("200 OK", cors_headers(), Some(json!({
+        "err": true,
+        "code": code,
+        "msg": msg
+    })))
+}
+
+// Validation error response listing every failing field in `errors`,
+// next to a `msg` summing them up
+pub fn respond_validation_err<S: Into<String>>(msg: S, errors: Vec<FieldError>) -> ! {
+    respond_fn_err(FnError::validation(msg, errors))
+}
+
+// Error response for a failed Cloudant request, carrying the HTTP status
+// and Cloudant's own error body next to the message when available
+pub fn respond_cloudant_err(err: ureq::Error) -> ! {
+    respond_fn_err(FnError::from(err))
+}
+
+// Error response for a `FnError`, the Cloudant ones keeping their status
+// and error body as in `respond_cloudant_err`. A preflight gets its CORS
+// headers, a detailed error its own status and fields.
+pub fn respond_fn_err(err: FnError) -> ! {
+    let err = match err {
+        FnError::Cloudant(err) => err,
+        FnError::Preflight => respond_preflight(),
+        FnError::Unauthorized(msg) => respond_unauthorized(msg),
+        FnError::Detailed { status, code, msg, fields } => {
+            let (code, msg) = past_deadline(code, msg);
+            let mut body = json!({
+                "err": true,
+                "code": code,
+                "msg": msg
+            });
+            body.as_object_mut().unwrap().extend(fields);
+            emit(status, cors_headers(), Some(body))
+        },
+        err => respond_err(err.code(), err.to_string())
+    };
+    let (code, msg) = past_deadline(ErrorCode::from_status(err.status), err.msg);
+    emit("200 OK", cors_headers(), Some(json!({
+        "err": true,
+        "code": code,
+        "msg": msg,
+        "status": err.status,
+        "cloudant_error": err.body
+    })))
+}
+
+// Error response for a write rejected with a 409 conflict, carrying the
+// document's `current_rev` so the client can retry without fetching it
+pub fn respond_conflict(err: ureq::Error, current_rev: Option<String>) -> ! {
+    respond_fn_err(FnError::conflict(err, current_rev))
+}
+
+// Error response for a request failing `verify_signature`, a real 401
+// sent before any work is done
+pub fn respond_unauthorized<S: Into<String>>(msg: S) -> ! {
+    emit("401 Unauthorized", cors_headers(), Some(json!({
+        "err": true,
+        "code": ErrorCode::Unauthorized,
+        "msg": msg.into()
+    })))
+}
+
+// IAM and Cloudant failures once the activation deadline has passed are
+// reported as such: most likely the request timed out because of it
+fn past_deadline(code: ErrorCode, msg: String) -> (ErrorCode, String) {
+    match code {
+        ErrorCode::IamFailure | ErrorCode::CloudantError if deadline_exceeded() => {
+            (ErrorCode::DeadlineExceeded, format!("Deadline exceeded: {}", msg))
+        },
+        code => (code, msg)
+    }
+}
+
+// Answer a CORS preflight (`OPTIONS`) request: the headers and no body
+pub fn respond_preflight() -> ! {
+    emit("204 No Content", cors_headers(), None)
+}
+
+// Web actions answer with the `{statusCode, headers, body}` envelope,
+// plain actions with the body alone. `WEB_ACTION=false` (or `0`) selects
+// the flat shape; the envelope is the default.
+fn web_action() -> bool {
+    !matches!(env::var("WEB_ACTION").as_deref(), Ok("false") | Ok("0"))
+}
+
+fn envelope(web_action: bool, status: &str, headers: sj::Value, body: Option<sj::Value>) -> sj::Value {
+    if !web_action {
+        return body.unwrap_or_else(|| json!({}))
+    }
+    let mut o = json!({
+        "statusCode": status,
+        "headers": headers
+    });
+    if let Some(body) = body {
+        o["body"] = body;
+    }
+    o
+}
+
+fn output(status: &str, mut headers: sj::Value, mut body: Option<sj::Value>) -> sj::Value {
+    // Echo the correlation id sent to IAM and Cloudant and logged with every
+    // line back to the caller, as `X-Request-ID` and as the body's
+    // `request_id`. Errors raised before `init_correlation_id` get a
+    // generated one, so every response carries one.
+    let id = init_correlation_id(&sj::Value::Null);
+    headers["X-Request-ID"] = json!(id);
+    if let Some(fields) = body.as_mut().and_then(|body| body.as_object_mut()) {
+        tag(fields, id, STATS.get().copied().unwrap_or(false));
+    }
+    envelope(web_action(), status, headers, body)
+}
+
+// Fields added to every body, with the counters of this function's warm
+// container before the current invocation when `stats` is asked
+fn tag(fields: &mut sj::Map<String, sj::Value>, id: &str, stats: bool) {
+    fields.insert("api_version".to_string(), json!(API_VERSION));
+    fields.insert("request_id".to_string(), json!(id));
+    if stats {
+        fields.insert("stats".to_string(), stats::stats());
+    }
+}
+
+// OpenWhisk rejects activation results over ~1 MB with an opaque platform
+// error. A larger output is replaced with a `RESPONSE_TOO_LARGE` error
+// response instead; `MAX_RESPONSE_BYTES` overrides the limit. Streamed
+// responses are stopped as soon as they cross it.
+pub const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+fn max_response_bytes() -> usize {
+    env::var("MAX_RESPONSE_BYTES").ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&value| value > 0)
+        .unwrap_or(MAX_RESPONSE_BYTES)
+}
+
+// The serialized output, or `Err` with the `RESPONSE_TOO_LARGE` response
+// replacing it
+fn serialize_within(o: &sj::Value, max: usize) -> Result<String, String> {
+    let serialized = to_output_string(o);
+    if serialized.len() <= max {
+        return Ok(serialized)
+    }
+    Err(too_large(&format!("{} bytes", serialized.len()), max))
+}
+
+fn too_large(size: &str, max: usize) -> String {
+    to_output_string(&output("200 OK", cors_headers(), Some(json!({
+        "err": true,
+        "code": ErrorCode::ResponseTooLarge,
+        "msg": format!(
+            "Response too large: {} exceeds the {} bytes limit, fetch less at a time with pagination (e.g. limit and skip)",
+            size, max
+        )
+    }))))
+}
+
+fn emit(status: &str, headers: sj::Value, body: Option<sj::Value>) -> ! {
+    let success = status.starts_with('2') && body.as_ref().and_then(|body| body.get("err")) != Some(&json!(true));
+    let o = output(status, headers, body);
+    match serialize_within(&o, max_response_bytes()) {
+        Ok(serialized) => finish(format!("{}\n", serialized).as_bytes(), success),
+        Err(too_large) => finish(format!("{}\n", too_large).as_bytes(), false)
+    }
+}
+
+// Push the serialized output to stdout, then count the invocation as a
+// `success` or not in the stats
+fn finish(output: &[u8], success: bool) -> ! {
+    let stdout = io::stdout();
+    if let Err(err) = write_output(&mut BufWriter::new(stdout.lock()), output) {
+        log(LogLevel::Error, json!({"msg": format!("Failure writing response: {}", err)}));
+        process::exit(exitcode::IOERR)
+    }
+    stats::record(success);
+    // The process is killed through the OS exitcode
+    process::exit(exitcode::OK)
+}
+
+// `process::exit` doesn't run destructors, so anything still buffered would
+// be lost: the output is flushed explicitly before the process exits
+fn write_output(out: &mut dyn Write, output: &[u8]) -> io::Result<()> {
+    out.write_all(output)?;
+    out.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_any_origin_by_default() {
+        let headers = cors_headers();
+        assert_eq!(headers["Access-Control-Allow-Origin"], "*");
+        assert!(headers["Access-Control-Allow-Methods"].as_str().unwrap().contains("OPTIONS"));
+    }
+
+    #[test]
+    fn picks_verbosity() {
+        assert!(verbose(&json!({}), ""));
+        assert!(!verbose(&json!({}), "verbose=false"));
+        assert!(!verbose(&json!({"accept": "application/json; verbose=false"}), ""));
+        assert!(verbose(&json!({"accept": "application/json; verbose=false"}), "verbose=true"));
+        assert!(verbose(&json!({"accept": "application/json"}), ""));
+    }
+
+    #[test]
+    fn pretty_prints_on_request() {
+        assert!(pretty("id=a&pretty=true"));
+        assert!(!pretty("id=a"));
+    }
+
+    #[test]
+    fn wraps_body_for_web_actions_only() {
+        let body = json!({"err": false, "data": []});
+        let wrapped = envelope(true, "200 OK", json!({}), Some(body.clone()));
+        assert_eq!(wrapped["statusCode"], "200 OK");
+        assert_eq!(wrapped["body"], body);
+        assert_eq!(envelope(false, "200 OK", json!({}), Some(body.clone())), body);
+        assert!(envelope(true, "204 No Content", json!({}), None).get("body").is_none());
+    }
+
+    #[test]
+    fn versions_and_tags_every_body() {
+        let o = output("200 OK", json!({}), Some(json!({"err": false})));
+        let body = if web_action() { &o["body"] } else { &o };
+        assert_eq!(body["api_version"], API_VERSION);
+        assert_eq!(body["request_id"], json!(crate::log::correlation_id()));
+        assert!(output("204 No Content", json!({}), None).get("body").is_none());
+    }
+
+    #[test]
+    fn reports_stats_on_request() {
+        let mut fields = sj::Map::new();
+        tag(&mut fields, "abc", false);
+        assert!(fields.get("stats").is_none());
+        tag(&mut fields, "abc", true);
+        assert_eq!(fields["request_id"], "abc");
+        assert!(fields["stats"]["invocations"].is_u64() && fields["stats"]["avg_latency_ms"].is_u64());
+    }
+
+    #[test]
+    fn writes_large_output_in_full() {
+        let rows: Vec<sj::Value> = (0..50_000).map(|n| json!({"id": format!("doc-{:08}", n), "value": n})).collect();
+        let o = envelope(true, "200 OK", json!({}), Some(json!({"err": false, "rows": rows})));
+        let mut out = io::BufWriter::with_capacity(8 * 1024, Vec::new());
+        write_output(&mut out, format!("{}\n", to_output_string(&o)).as_bytes()).unwrap();
+        let written = out.into_inner().unwrap();
+        assert!(written.len() > 1024 * 1024);
+        assert_eq!(written.last(), Some(&b'\n'));
+        assert_eq!(sj::from_slice::<sj::Value>(&written).unwrap(), o);
+    }
+
+    #[test]
+    fn replaces_oversized_output() {
+        let o = json!({"err": false, "rows": ["a".repeat(2048)]});
+        assert_eq!(serialize_within(&o, 4096), Ok(to_output_string(&o)));
+        let replaced: sj::Value = sj::from_str(&serialize_within(&o, 1024).unwrap_err()).unwrap();
+        let body = if web_action() { &replaced["body"] } else { &replaced };
+        assert_eq!(body["code"], "RESPONSE_TOO_LARGE");
+        assert!(body["msg"].as_str().unwrap().contains("pagination"));
+    }
+
+    #[test]
+    fn splices_streamed_field() {
+        let body = json!({"err": false, "data": STREAM_MARKER, "msg": "done"});
+        let o = envelope(true, "200 OK", json!({}), Some(body));
+        let mut out = Vec::new();
+        write_streamed(&mut out, &o, |out| {
+            out.write_all(br#"{"rows":[1,2]}"#).unwrap();
+            Ok(json!({"truncated": true}).as_object().unwrap().clone())
+        }).unwrap();
+        let written: sj::Value = sj::from_slice(&out).unwrap();
+        assert_eq!(written["body"], json!({"err": false, "data": {"rows": [1, 2]}, "truncated": true, "msg": "done"}));
+    }
+
+    #[test]
+    fn stops_oversized_stream() {
+        let o = envelope(true, "200 OK", json!({}), Some(json!({"data": STREAM_MARKER})));
+        let mut rows = 0;
+        let mut out = Bounded { buf: Vec::new(), max: 1024, exceeded: false };
+        let res = write_streamed(&mut out, &o, |out| {
+            loop {
+                out.write_all(b"{\"id\":\"doc\"},").map_err(|err| err.to_string())?;
+                rows += 1;
+            }
+        });
+        assert!(res.is_err() && out.exceeded);
+        assert!(out.buf.len() <= 1024 && rows < 1024);
+
+        let mut out = Bounded { buf: Vec::new(), max: 1024, exceeded: false };
+        write_streamed(&mut out, &o, |out| {
+            out.write_all(b"[]").unwrap();
+            Ok(sj::Map::new())
+        }).unwrap();
+        assert!(!out.exceeded);
+        assert_eq!(sj::from_slice::<sj::Value>(&out.buf).unwrap()["body"]["data"], json!([]));
+    }
+}
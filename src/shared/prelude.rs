@@ -0,0 +1,65 @@
+use crate::config::{read_input, Config, ICFRawInput};
+use crate::error::FnError;
+use crate::log::init_correlation_id;
+use crate::output::init_verbosity;
+use crate::signature::verify_signature;
+
+// Steps every function runs before its own work, kept in one place so a
+// new cross-cutting step is added once: parse the raw HTTP request
+// OpenWhisk passes as the first argument, then `accept` it
+pub fn read_request() -> Result<ICFRawInput, FnError> {
+    accept(read_input()?)
+}
+
+// `read_request`, then the connection settings: payload fields merged with
+// env overrides
+pub fn prelude() -> Result<(ICFRawInput, Config), FnError> {
+    let i = read_request()?;
+    let config = Config::from_input(&i)?;
+    Ok((i, config))
+}
+
+// `prelude` of server-level functions, no database needed
+pub fn server_prelude() -> Result<(ICFRawInput, Config), FnError> {
+    let i = read_request()?;
+    let config = Config::server_from_input(&i)?;
+    Ok((i, config))
+}
+
+// CORS preflight requests from browser clients are answered right away.
+// Otherwise the correlation id of the structured logs and the response
+// verbosity (`verbose=false` or `Accept`) are set up, and callers must
+// sign the body when `HMAC_SECRET` is set.
+fn accept(i: ICFRawInput) -> Result<ICFRawInput, FnError> {
+    if i.__ow_method.eq_ignore_ascii_case("options") {
+        return Err(FnError::Preflight)
+    }
+    init_correlation_id(&i.__ow_headers);
+    init_verbosity(&i.__ow_headers, &i.__ow_query);
+    verify_signature(&i).map_err(FnError::Unauthorized)?;
+    Ok(i)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{self as sj, json};
+
+    fn input(method: &str) -> ICFRawInput {
+        sj::from_value(json!({
+            "__ow_body": "",
+            "__ow_headers": {},
+            "__ow_method": method,
+            "__ow_path": "",
+            "__ow_query": ""
+        })).unwrap()
+    }
+
+    #[test]
+    fn answers_preflight_first() {
+        assert!(matches!(accept(input("OPTIONS")), Err(FnError::Preflight)));
+        assert!(matches!(accept(input("options")), Err(FnError::Preflight)));
+        assert_eq!(accept(input("GET")).unwrap().__ow_method, "GET");
+    }
+}
@@ -0,0 +1,99 @@
+use serde_json::{self as sj};
+use url::form_urlencoded;
+
+// Look up a parameter in a raw, URL-encoded query string such as the
+// `__ow_query` field of a web action ("name=Jane&limit=10").
+// The value is returned percent-decoded.
+pub fn query_param(query: &str, name: &str) -> Option<String> {
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+// Every parameter of a raw query string, in order, percent-decoded
+pub fn query_params(query: &str) -> Vec<(String, String)> {
+    form_urlencoded::parse(query.as_bytes())
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect()
+}
+
+// Whether a boolean query parameter is set, i.e. `name=true`
+pub fn query_flag(query: &str, name: &str) -> bool {
+    query_param(query, name).as_deref() == Some("true")
+}
+
+// Optional `w` write quorum forwarded to Cloudant: how many replicas must
+// acknowledge a write before it returns. A higher `w` is more durable but
+// waits for slower replicas; Cloudant's default quorum applies when absent.
+pub fn write_quorum(query: &str) -> Result<Option<u32>, String> {
+    match query_param(query, "w") {
+        None => Ok(None),
+        Some(w) => match w.parse::<u32>() {
+            Ok(w) if w > 0 => Ok(Some(w)),
+            _ => Err(format!("Invalid w query param: {}, expected a positive integer", w))
+        }
+    }
+}
+
+// Params holding document ids or database names (`startkey`, `endkey`)
+// are sent JSON-encoded. Raw values (`startkey=abc`) are encoded, JSON
+// string literals (`startkey="abc"`) are checked and kept.
+pub fn json_string_param(value: &str) -> Result<String, String> {
+    if !value.starts_with('"') {
+        return Ok(sj::to_string(value).unwrap())
+    }
+    match sj::from_str::<String>(value) {
+        Ok(id) => Ok(sj::to_string(&id).unwrap()),
+        Err(err) => Err(format!("Invalid key {}: not a valid JSON string: {}", value, err))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_decoded_param() {
+        let query = "name=Jane&partition=team%2Fa&empty=";
+        assert_eq!(query_param(query, "name"), Some("Jane".to_string()));
+        assert_eq!(query_param(query, "partition"), Some("team/a".to_string()));
+        assert_eq!(query_param(query, "empty"), Some("".to_string()));
+        assert_eq!(query_param(query, "missing"), None);
+        assert_eq!(query_param("", "name"), None);
+    }
+
+    #[test]
+    fn lists_all_params() {
+        let params = query_params("filter=app%2Fby_owner&owner=jane");
+        assert_eq!(params, vec![
+            ("filter".to_string(), "app/by_owner".to_string()),
+            ("owner".to_string(), "jane".to_string())
+        ]);
+        assert!(query_params("").is_empty());
+    }
+
+    #[test]
+    fn reads_boolean_flag() {
+        assert!(query_flag("timings=true", "timings"));
+        assert!(!query_flag("timings=false", "timings"));
+        assert!(!query_flag("timings=1", "timings"));
+        assert!(!query_flag("", "timings"));
+    }
+
+    #[test]
+    fn reads_write_quorum() {
+        assert_eq!(write_quorum("id=a"), Ok(None));
+        assert_eq!(write_quorum("w=2"), Ok(Some(2)));
+        assert!(write_quorum("w=0").is_err());
+        assert!(write_quorum("w=-1").is_err());
+        assert!(write_quorum("w=majority").is_err());
+    }
+
+    #[test]
+    fn encodes_string_keys() {
+        assert_eq!(json_string_param("todo:"), Ok("\"todo:\"".to_string()));
+        assert_eq!(json_string_param("\"todo:\""), Ok("\"todo:\"".to_string()));
+        assert!(json_string_param("\"todo:").is_err());
+    }
+}
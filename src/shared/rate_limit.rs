@@ -0,0 +1,153 @@
+use std::{env, fs, path::{Path, PathBuf}, sync::{Mutex, OnceLock}, time::{SystemTime, UNIX_EPOCH}};
+use serde::{Deserialize, Serialize};
+use serde_json::{self as sj};
+use crate::iam::sha256_hex;
+
+// Outbound Cloudant request budget of a credential in this container, from
+// the `MAX_RPS` env var (requests per second, unset or 0 for no limit).
+// OpenWhisk runs many containers side by side and each keeps its own
+// buckets: this smooths the rate of a warm container, it is not a global
+// limit. The action proxy runs the binary once per activation, so a bucket
+// is carried from one process to the next in a file of the container's temp
+// dir, one per credential named after its SHA-256, as the IAM refresh
+// tokens are.
+static LIMITER: OnceLock<Option<Mutex<Limiter>>> = OnceLock::new();
+
+// Credential the requests of this activation are counted against, see
+// `set_key`
+static KEY: OnceLock<String> = OnceLock::new();
+
+struct Limiter {
+    file: PathBuf,
+    bucket: TokenBucket
+}
+
+// Count the Cloudant requests of this activation against `credential`: the
+// apikey, or the username with `AUTH_MODE=basic`. Set once the connection
+// settings are read, before the first request.
+pub fn set_key(credential: &str) {
+    let _ = KEY.set(credential.to_string());
+}
+
+fn max_rps() -> Option<f64> {
+    env::var("MAX_RPS").ok()?.trim().parse::<f64>().ok().filter(|rps| *rps > 0.0)
+}
+
+fn bucket_file(key: &str) -> PathBuf {
+    env::temp_dir().join(format!("fn-rate-limit-{}.json", sha256_hex(key)))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+// Pick up the bucket left by the previous activations, a full one on cold
+// start. The rate is always the current `MAX_RPS`.
+fn load(file: &Path, rate: f64) -> TokenBucket {
+    let saved = fs::read(file).ok()
+        .and_then(|raw| sj::from_slice::<TokenBucket>(&raw).ok());
+    let mut bucket = TokenBucket::new(rate, now_ms());
+    if let Some(saved) = saved {
+        bucket.tokens = saved.tokens.min(bucket.capacity());
+        bucket.refilled_ms = saved.refilled_ms;
+    }
+    bucket
+}
+
+// Take a token for one Cloudant request. `false` when the bucket is empty:
+// the request must not be sent, the caller answers 429 right away rather
+// than waiting for the bucket to refill.
+pub fn acquire() -> bool {
+    let limiter = LIMITER.get_or_init(|| max_rps().map(|rps| {
+        let file = bucket_file(KEY.get().map(String::as_str).unwrap_or_default());
+        let bucket = load(&file, rps);
+        Mutex::new(Limiter { file, bucket })
+    }));
+    let mut limiter = match limiter {
+        Some(limiter) => limiter.lock().unwrap_or_else(|err| err.into_inner()),
+        None => return true
+    };
+    let taken = limiter.bucket.take(now_ms());
+    // Failing to save the bucket only forgets the tokens taken
+    let _ = fs::write(&limiter.file, sj::to_vec(&limiter.bucket).unwrap());
+    taken
+}
+
+// Token bucket refilled at `rate` tokens per second, holding at most one
+// second worth of them so a burst can't exceed `rate` requests. A rate
+// below 1 still holds one token, or no request could ever be sent.
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    // Epoch millis of the last refill
+    refilled_ms: u64
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now_ms: u64) -> TokenBucket {
+        TokenBucket { rate, tokens: rate.max(1.0), refilled_ms: now_ms }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    // Take a token, `false` when none is left
+    fn take(&mut self, now_ms: u64) -> bool {
+        let elapsed = now_ms.saturating_sub(self.refilled_ms) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity());
+        self.refilled_ms = self.refilled_ms.max(now_ms);
+        if self.tokens < 1.0 {
+            return false
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_at_the_configured_rate() {
+        let start = 1_000_000;
+        let mut bucket = TokenBucket::new(2.0, start);
+        assert!(bucket.take(start) && bucket.take(start));
+        assert!(!bucket.take(start));
+        assert!(!bucket.take(start + 499));
+        assert!(bucket.take(start + 500));
+        let later = start + 10_000;
+        assert!(bucket.take(later) && bucket.take(later) && !bucket.take(later));
+    }
+
+    #[test]
+    fn allows_rates_below_one() {
+        let start = 1_000_000;
+        let mut bucket = TokenBucket::new(0.5, start);
+        assert!(bucket.take(start));
+        assert!(!bucket.take(start + 1_000));
+        assert!(bucket.take(start + 2_000));
+        assert!(!bucket.take(start + 2_000));
+        let later = start + 60_000;
+        assert!(bucket.take(later) && !bucket.take(later));
+    }
+
+    #[test]
+    fn survives_the_process() {
+        let mut bucket = TokenBucket::new(5.0, 1_000_000);
+        bucket.take(1_000_000);
+        let saved: TokenBucket = sj::from_slice(&sj::to_vec(&bucket).unwrap()).unwrap();
+        assert_eq!(saved, bucket);
+        assert_eq!(saved.tokens, 4.0);
+    }
+
+    #[test]
+    fn keys_buckets_by_credential() {
+        let file = bucket_file("secret-apikey");
+        assert_ne!(file, bucket_file("other-apikey"));
+        assert!(!file.to_string_lossy().contains("secret-apikey"));
+    }
+}
@@ -0,0 +1,61 @@
+use std::env;
+use ring::hmac;
+use crate::config::ICFRawInput;
+
+// Optional verification of the caller for publicly exposed functions: when
+// `HMAC_SECRET` is set, the `X-Signature` header must hold the hex
+// HMAC-SHA256 of the raw `__ow_body`, optionally prefixed with `sha256=`.
+// Without the secret every request is accepted.
+pub fn verify_signature(input: &ICFRawInput) -> Result<(), String> {
+    match env::var("HMAC_SECRET") {
+        Ok(secret) if !secret.is_empty() => {
+            let signature = input.__ow_headers.get("x-signature").and_then(|signature| signature.as_str());
+            check_signature(secret.as_bytes(), signature, &input.__ow_body)
+        },
+        _ => Ok(())
+    }
+}
+
+// `hmac::verify` compares the tags in constant time, so the time taken
+// doesn't tell how much of a forged signature was right
+fn check_signature(secret: &[u8], signature: Option<&str>, body: &str) -> Result<(), String> {
+    let signature = signature.ok_or("Missing X-Signature header")?;
+    let hex = signature.trim();
+    let tag = decode_hex(hex.strip_prefix("sha256=").unwrap_or(hex))
+        .ok_or("Invalid X-Signature header: expected a hex HMAC-SHA256")?;
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::verify(&key, body.as_bytes(), &tag)
+        .map_err(|_| "Invalid X-Signature header: signature does not match the body".to_string())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None
+    }
+    (0..hex.len()).step_by(2)
+        .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).ok())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &[u8], body: &str) -> String {
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret), body.as_bytes());
+        tag.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn checks_body_signature() {
+        let body = "eyJ0YXNrIjoid3JpdGUgZG9jcyJ9";
+        let signature = sign(b"secret", body);
+        assert!(check_signature(b"secret", Some(&signature), body).is_ok());
+        assert!(check_signature(b"secret", Some(&format!("sha256={}", signature)), body).is_ok());
+        assert!(check_signature(b"other", Some(&signature), body).is_err());
+        assert!(check_signature(b"secret", Some(&signature), "e30=").is_err());
+        assert!(check_signature(b"secret", Some("not hex"), body).is_err());
+        assert!(check_signature(b"secret", None, body).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::{env, fs, path::PathBuf, sync::{atomic::{AtomicU64, Ordering}, Once, OnceLock}, time::Instant};
+use serde::{Deserialize, Serialize};
+use serde_json::{self as sj, json};
+
+// Invocation counters of the warm container, a poor man's Prometheus: any
+// function called with `stats=true` adds its own counters to its body, see
+// `output`. The action proxy runs the binary once per activation, so the
+// counters are carried from one process to the next in a file of the
+// container's temp dir: they reset on cold start, and every action (each
+// its own container) counts its own invocations.
+static INVOCATIONS: AtomicU64 = AtomicU64::new(0);
+static SUCCESSES: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+static LATENCY_MS: AtomicU64 = AtomicU64::new(0);
+
+// Start of the current invocation, see `Timings::start`
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static LOADED: Once = Once::new();
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+struct Snapshot {
+    invocations: u64,
+    successes: u64,
+    errors: u64,
+    // Sum of the latencies, averaged when reported
+    latency_ms: u64
+}
+
+fn stats_file() -> PathBuf {
+    env::temp_dir().join("fn-stats.json")
+}
+
+// Pick up the counters left by the previous invocations, once per process
+fn load() {
+    LOADED.call_once(|| {
+        let snapshot = fs::read(stats_file()).ok()
+            .and_then(|raw| sj::from_slice::<Snapshot>(&raw).ok())
+            .unwrap_or_default();
+        INVOCATIONS.fetch_add(snapshot.invocations, Ordering::Relaxed);
+        SUCCESSES.fetch_add(snapshot.successes, Ordering::Relaxed);
+        ERRORS.fetch_add(snapshot.errors, Ordering::Relaxed);
+        LATENCY_MS.fetch_add(snapshot.latency_ms, Ordering::Relaxed);
+    });
+}
+
+fn snapshot() -> Snapshot {
+    load();
+    Snapshot {
+        invocations: INVOCATIONS.load(Ordering::Relaxed),
+        successes: SUCCESSES.load(Ordering::Relaxed),
+        errors: ERRORS.load(Ordering::Relaxed),
+        latency_ms: LATENCY_MS.load(Ordering::Relaxed)
+    }
+}
+
+pub fn mark_started() {
+    STARTED.get_or_init(Instant::now);
+}
+
+// Count the current invocation once its response is written. Failing to
+// save the counters only loses them, it doesn't fail the invocation.
+pub fn record(success: bool) {
+    load();
+    let latency_ms = STARTED.get().map(|started| started.elapsed().as_millis() as u64).unwrap_or_default();
+    INVOCATIONS.fetch_add(1, Ordering::Relaxed);
+    if success { &SUCCESSES } else { &ERRORS }.fetch_add(1, Ordering::Relaxed);
+    LATENCY_MS.fetch_add(latency_ms, Ordering::Relaxed);
+    let _ = fs::write(stats_file(), sj::to_vec(&snapshot()).unwrap());
+}
+
+// The counters as reported with `stats=true`
+pub fn stats() -> sj::Value {
+    to_json(&snapshot())
+}
+
+fn to_json(snapshot: &Snapshot) -> sj::Value {
+    json!({
+        "invocations": snapshot.invocations,
+        "successes": snapshot.successes,
+        "errors": snapshot.errors,
+        "avg_latency_ms": snapshot.latency_ms.checked_div(snapshot.invocations).unwrap_or_default()
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_latency() {
+        let snapshot = Snapshot { invocations: 4, successes: 3, errors: 1, latency_ms: 200 };
+        assert_eq!(to_json(&snapshot), json!({"invocations": 4, "successes": 3, "errors": 1, "avg_latency_ms": 50}));
+        assert_eq!(to_json(&Snapshot::default())["avg_latency_ms"], 0);
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde_json::{self as sj, json};
+use crate::stats;
+
+// Wall-clock time spent on IAM and Cloudant requests during an activation,
+// reported as `timings: {iam_ms, db_ms, total_ms}` when `timings=true`
+pub struct Timings {
+    started: Instant,
+    iam_ms: u128,
+    db_ms: u128
+}
+
+impl Timings {
+    pub fn start() -> Timings {
+        stats::mark_started();
+        Timings { started: Instant::now(), iam_ms: 0, db_ms: 0 }
+    }
+
+    // Run an IAM request, adding its duration to `iam_ms`
+    pub fn iam<T, F: FnOnce() -> T>(&mut self, call: F) -> T {
+        let started = Instant::now();
+        let result = call();
+        self.iam_ms += started.elapsed().as_millis();
+        result
+    }
+
+    // Run a Cloudant request, adding its duration to `db_ms`
+    pub fn db<T, F: FnOnce() -> T>(&mut self, call: F) -> T {
+        let started = Instant::now();
+        let result = call();
+        self.db_ms += started.elapsed().as_millis();
+        result
+    }
+
+    pub fn to_json(&self) -> sj::Value {
+        json!({
+            "iam_ms": self.iam_ms as u64,
+            "db_ms": self.db_ms as u64,
+            "total_ms": self.started.elapsed().as_millis() as u64
+        })
+    }
+}
+
+// Format `time` as an RFC 3339 UTC timestamp, e.g. `2021-03-20T11:25:35Z`
+pub fn rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, rest / 3600, rest % 3600 / 60, rest % 60
+    )
+}
+
+// Days since 1970-01-01 to a (year, month, day) proleptic Gregorian date,
+// see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn accumulates_integer_millis() {
+        let mut timings = Timings::start();
+        timings.iam(|| thread::sleep(Duration::from_millis(5)));
+        timings.db(|| thread::sleep(Duration::from_millis(5)));
+        timings.db(|| thread::sleep(Duration::from_millis(5)));
+
+        let json = timings.to_json();
+        assert!(json["iam_ms"].as_u64().unwrap() >= 5);
+        assert!(json["db_ms"].as_u64().unwrap() >= 10);
+        assert!(json["total_ms"].as_u64().unwrap() >= 15);
+    }
+
+    #[test]
+    fn formats_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(1616239535)), "2021-03-20T11:25:35Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00Z");
+    }
+}
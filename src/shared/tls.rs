@@ -0,0 +1,80 @@
+use std::{env, sync::Arc};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, Error, SignatureScheme};
+
+// Self-hosted CouchDB often runs with self-signed certificates:
+// `ALLOW_INVALID_CERTS=1` (or `true`) turns certificate verification off.
+// Strict verification is the default.
+pub fn allow_invalid_certs() -> bool {
+    matches!(env::var("ALLOW_INVALID_CERTS").as_deref(), Ok("1") | Ok("true"))
+}
+
+// TLS settings accepting any server certificate. Handshake signatures are
+// still checked, so the connection is encrypted, just not authenticated.
+pub fn insecure_tls_config() -> Arc<ClientConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default TLS versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn accepts_any_certificate() {
+        let verifier = AcceptAnyCert(Arc::new(ring::default_provider()));
+        let name = ServerName::try_from("couchdb.local".to_string()).unwrap();
+        let bogus = CertificateDer::from(vec![0u8; 16]);
+        assert!(verifier.verify_server_cert(&bogus, &[], &name, &[], UnixTime::now()).is_ok());
+        assert!(!verifier.supported_verify_schemes().is_empty());
+        insecure_tls_config();
+    }
+}
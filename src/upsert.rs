@@ -0,0 +1,144 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, current_rev, decode_body, doc_url, ErrorCode, FnError, prelude, query_flag, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (document GET):
+{
+    "_id": "exampleid",
+    "_rev": "1-967a00dff5e02add41819138abb3284d",
+    "task": "write docs",
+    "done": false
+} */
+#[derive(Deserialize, Debug)]
+struct CDBDocument {
+    _rev: String
+}
+
+/* Example HTTP response from IBM Cloudant (document PUT):
+{
+    "id": "exampleid",
+    "ok": true,
+    "rev": "2-7051cbe5c8faecd085a3fa619e6e6337"
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    id: String,
+    ok: bool,
+    rev: String
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes
+    let document: sj::Value = sj::from_slice(&bytes)?;
+
+    // The document must be a JSON object carrying its `_id`
+    let mut document = match document {
+        sj::Value::Object(map) => map,
+        _ => {
+            // Document is not a JSON object
+            // Return error message
+            return Err(FnError::new(ErrorCode::ValidationError, "Failure validating document: expected a JSON object"))
+        }
+    };
+    let id = match document.get("_id").and_then(|id| id.as_str()) {
+        Some(id) if !id.is_empty() => id.to_string(),
+        _ => {
+            // Document without an _id to upsert
+            // Return error message
+            return Err(FnError::new(ErrorCode::ValidationError, "Missing _id: upsert requires a document with a string _id"))
+        }
+    };
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // `dry_run=true`: the request parsed and IAM auth works, stop before
+    // writing anything to Cloudant
+    if query_flag(&i.__ow_query, "dry_run") {
+        return Ok(json!({
+            "err": false,
+            "msg": "upsert dry run complete!",
+            "dry_run": true,
+            "valid": true
+        }))
+    }
+
+    // Upsert the document: look up its current rev, then PUT it with that
+    // rev (update) or without a rev (create). If the document is created
+    // concurrently between the GET and the PUT, Cloudant answers 409 and
+    // the whole lookup + write is retried once.
+    let uri = doc_url(&config.db_url, &config.database, &id);
+    let mut retried = false;
+    let (cdb_resp, updated) = loop {
+        // Fetch the current rev, a 404 means the document is new
+        let rev = match timings.db(|| cloudant_call("GET", || config.request("GET", &uri)
+            .set("Authorization", &authorization)
+            .call())) {
+                Ok(res) => read_cloudant_json::<CDBDocument>(res)
+                    .map(|doc| Some(doc._rev))
+                    .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?,
+                Err(ureq::Error::Status(404, _)) => None,
+                Err(err) => {
+                    // Failure querying Cloudant, report its error body
+                    return Err(FnError::from(err))
+                }
+            };
+
+        // Write the document, carrying the current rev when updating
+        match &rev {
+            Some(rev) => document.insert("_rev".to_string(), json!(rev)),
+            None => document.remove("_rev")
+        };
+        let request = config.request("PUT", &uri)
+            .set("Authorization", &authorization);
+        match timings.db(|| cloudant_call("PUT", || send_json_body(request.clone(), &json!(document)))) {
+                Ok(res) => break (res, rev.is_some()),
+                Err(ureq::Error::Status(409, _)) if !retried => retried = true,
+                Err(err @ ureq::Error::Status(409, _)) => {
+                    // Still conflicting after the retry, hand out the current rev
+                    let rev = current_rev(config.request("HEAD", &uri).set("Authorization", &authorization));
+                    return Err(FnError::conflict(err, rev))
+                },
+                Err(err) => {
+                    // Failure querying Cloudant, report its error body
+                    return Err(FnError::from(err))
+                }
+            }
+    };
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "upsert execution complete!",
+        "operation": if updated { "update" } else { "create" },
+        "upserted_record": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
@@ -0,0 +1,95 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, ErrorCode, FnError, join_url, query_flag, query_param, read_cloudant_json, respond_result, server_prelude, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (`_uuids?count=2`):
+{
+    "uuids": [
+        "75480ca477454894678e22eec6002413",
+        "75480ca477454894678e22eec600250b"
+    ]
+} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    uuids: Vec<String>
+}
+
+// Most UUIDs handed out by a single activation
+const MAX_UUIDS: u32 = 100;
+
+// Number of UUIDs asked for by the `count` query param, 1 by default and
+// clamped to `MAX_UUIDS`
+fn uuid_count(count: Option<String>) -> Result<u32, String> {
+    match count.map(|count| count.parse::<u32>()) {
+        None => Ok(1),
+        Some(Ok(count)) if count > 0 => Ok(count.min(MAX_UUIDS)),
+        Some(_) => Err("Invalid count query param: expected a positive integer".to_string())
+    }
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, no database needed at the server level
+    let (i, config) = server_prelude()?;
+
+    // How many UUIDs to generate
+    let count = uuid_count(query_param(&i.__ow_query, "count"))
+        .map_err(|msg| FnError::new(ErrorCode::BadRequest, msg))?;
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Ask Cloudant for UUIDs
+    let uri = join_url(&config.db_url, "_uuids");
+    let cdb_resp = timings.db(|| cloudant_call("GET", || config.request("GET", &uri)
+        .query("count", &count.to_string())
+        .set("Authorization", &authorization)
+        .call()))?;
+
+    // Cloudant's clock, from the `Date` header of its answer
+    let server_time = cdb_resp.header("Date").map(|date| date.to_string());
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "uuids execution complete!",
+        "uuids": cdb_data.uuids,
+        "server_time": server_time
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_uuid_count() {
+        assert_eq!(uuid_count(None), Ok(1));
+        assert_eq!(uuid_count(Some("5".to_string())), Ok(5));
+        assert_eq!(uuid_count(Some("5000".to_string())), Ok(MAX_UUIDS));
+        assert!(uuid_count(Some("0".to_string())).is_err());
+        assert!(uuid_count(Some("-1".to_string())).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, cloudant_error, decode_body, ErrorCode, FnError, join_url, prelude, query_flag, read_cloudant_json, respond_result, send_json_body, Timings};
+
+
+/* Example HTTP response from IBM Cloudant (`_explain`):
+{
+    "dbname": "todos",
+    "index": {
+        "ddoc": null,
+        "name": "_all_docs",
+        "type": "special",
+        "def": {"fields": [{"_id": "asc"}]}
+    },
+    "selector": {"done": {"$eq": false}},
+    "opts": {"use_index": [], "bookmark": "nil", "limit": 25, "skip": 0},
+    "limit": 25,
+    "skip": 0,
+    "fields": "all_fields"
+}
+A malformed selector is answered with a 400, e.g.
+{"error": "invalid_operator", "reason": "Invalid operator: $eqq"} */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    index: CDBIndex,
+    selector: sj::Value
+}
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBIndex {
+    ddoc: Option<String>,
+    name: String,
+    #[serde(rename = "type")]
+    index_type: String,
+    def: sj::Value
+}
+
+// The query to check: a `_find` body holding a `selector`, e.g.
+// `{"selector": {"done": false}, "sort": [...]}`. It is never run, only
+// explained, so no documents are read.
+fn explain_query(mut query: sj::Value) -> Result<sj::Value, String> {
+    if !query.get("selector").is_some_and(|selector| selector.is_object()) {
+        return Err("Missing selector: expected a JSON object with a selector object".to_string())
+    }
+    query["limit"] = json!(0);
+    Ok(query)
+}
+
+// Without a usable index Cloudant falls back to `_all_docs` (type
+// `special`) and scans every document
+fn full_scan(index: &CDBIndex) -> bool {
+    index.index_type == "special"
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Decode input request body from base64
+    let bytes = decode_body(&i.__ow_body)?;
+
+    // Deserialize decoded bytes
+    let query = explain_query(sj::from_slice(&bytes)?)
+        .map_err(|msg| FnError::new(ErrorCode::BadRequest, msg))?;
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Ask Cloudant how it would run the query
+    let uri = join_url(&config.db_url, &format!("{}/_explain", &config.database));
+    let request = config.request("POST", &uri)
+        .set("Authorization", &authorization);
+    let cdb_resp = match timings.db(|| cloudant_call("POST", || send_json_body(request.clone(), &query))) {
+            Ok(res) => res,
+            Err(err @ ureq::Error::Status(400, _)) => {
+                // Malformed selector: an answer, not a failure
+                let err = cloudant_error(err);
+                let mut body = json!({
+                    "err": false,
+                    "msg": "validate_selector execution complete!",
+                    "valid": false,
+                    "reason": err.msg,
+                    "cloudant_error": err.body
+                });
+                if query_flag(&i.__ow_query, "timings") {
+                    body["timings"] = timings.to_json();
+                }
+                return Ok(body)
+            },
+            Err(err) => return Err(FnError::from(err))
+        };
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct, with the index Cloudant would pick
+    let mut body = json!({
+        "err": false,
+        "msg": "validate_selector execution complete!",
+        "valid": true,
+        "full_scan": full_scan(&cdb_data.index),
+        "index": cdb_data.index,
+        "selector": cdb_data.selector
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explains_without_reading_documents() {
+        let query = explain_query(json!({"selector": {"done": false}, "limit": 50})).unwrap();
+        assert_eq!(query["limit"], 0);
+        assert_eq!(query["selector"], json!({"done": false}));
+        assert!(explain_query(json!({"done": false})).is_err());
+        assert!(explain_query(json!({"selector": "done"})).is_err());
+        assert!(explain_query(json!([])).is_err());
+    }
+
+    #[test]
+    fn flags_full_scans() {
+        let index: CDBIndex = sj::from_value(json!({
+            "ddoc": null, "name": "_all_docs", "type": "special", "def": {"fields": [{"_id": "asc"}]}
+        })).unwrap();
+        assert!(full_scan(&index));
+        let index: CDBIndex = sj::from_value(json!({
+            "ddoc": "_design/a5f4711fc9448864a13c81dc71e660b524d7410c", "name": "done-index", "type": "json", "def": {"fields": [{"done": "asc"}]}
+        })).unwrap();
+        assert!(!full_scan(&index));
+    }
+}
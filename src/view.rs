@@ -0,0 +1,136 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{cloudant_call, ErrorCode, FnError, join_url, path_segment, prelude, query_flag, query_param, read_cloudant_json, respond_result, Timings};
+
+
+/* Example HTTP response from IBM Cloudant:
+{
+    "total_rows": 2,
+    "offset": 0,
+    "rows": [
+        {
+            "id": "exampleid",
+            "key": "write docs",
+            "value": 1
+        }
+    ]
+}
+Reduced views only return `rows`, e.g. [{"key": null, "value": 2}]. */
+#[derive(Deserialize, Serialize, Debug)]
+struct CDBResponse {
+    total_rows: Option<i64>,
+    offset: Option<i64>,
+    rows: Vec<sj::Value>
+}
+
+// View keys must be sent JSON-encoded. Values that already parse as JSON
+// (e.g. `"abc"`, `42`, `["a", 1]`) are forwarded as-is, anything else
+// is encoded as a JSON string.
+fn json_key(value: &str) -> String {
+    match sj::from_str::<sj::Value>(value) {
+        Ok(_) => value.to_string(),
+        Err(_) => sj::to_string(value).unwrap()
+    }
+}
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM and Cloudant, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, then read the connection
+    // settings, see `shared::prelude`
+    let (i, config) = prelude()?;
+
+    // Read the design document and view names from the query string
+    let (design, view) = match (
+        query_param(&i.__ow_query, "design"),
+        query_param(&i.__ow_query, "view")
+    ) {
+        (Some(d), Some(v)) if !d.is_empty() && !v.is_empty() => (d, v),
+        _ => {
+            // Design document or view name not provided
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Missing design or view query param"))
+        }
+    };
+
+    // Both names stay single path segments of the database, see
+    // `path_segment`
+    let segment = |name: &str| path_segment(name).map_err(|msg| FnError::new(ErrorCode::BadRequest, msg));
+    let path = format!("{}/_design/{}/_view/{}", &config.database, segment(&design)?, segment(&view)?);
+
+    // Collect the optional view params forwarded to Cloudant
+    let mut params: Vec<(&str, String)> = Vec::new();
+    for name in &["key", "startkey", "endkey"] {
+        if let Some(value) = query_param(&i.__ow_query, name) {
+            params.push((name, json_key(&value)));
+        }
+    }
+    for name in &["reduce", "group"] {
+        if let Some(value) = query_param(&i.__ow_query, name) {
+            if value != "true" && value != "false" {
+                // Boolean view param with an unexpected value
+                // Return error message
+                return Err(FnError::new(ErrorCode::BadRequest, format!("Invalid {} query param: expected true or false", name)))
+            }
+            params.push((name, value));
+        }
+    }
+
+    // Optional `update` param: `false` answers from the index as it is and
+    // `lazy` refreshes it after answering, trading freshness for latency.
+    // Such results may not reflect the latest writes. The legacy
+    // `stale=ok` / `stale=update_after` map to `false` / `lazy`.
+    let update = match query_param(&i.__ow_query, "stale").as_deref() {
+        Some("ok") => Some("false".to_string()),
+        Some("update_after") => Some("lazy".to_string()),
+        Some(_) => {
+            // Legacy stale param with an unexpected value
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Invalid stale query param: expected ok or update_after"))
+        },
+        None => query_param(&i.__ow_query, "update")
+    };
+    if let Some(value) = update {
+        if !matches!(value.as_str(), "true" | "false" | "lazy") {
+            // Update mode Cloudant doesn't know
+            // Return error message
+            return Err(FnError::new(ErrorCode::BadRequest, "Invalid update query param: expected true, false or lazy"))
+        }
+        params.push(("update", value));
+    }
+
+    // Request IAM token from IBM Cloud, or use basic auth with AUTH_MODE=basic
+    let authorization = timings.iam(|| config.authorization()).map_err(FnError::Iam)?;
+
+    // Query the Cloudant view
+    let uri = join_url(&config.db_url, &path);
+    let cdb_resp = timings.db(|| cloudant_call("GET", || params.iter()
+        .fold(config.request("GET", &uri), |req, (name, value)| req.query(name, value))
+        .set("Authorization", &authorization)
+        .call()))?;
+
+    // Deserialize Cloudant response
+    let cdb_data = read_cloudant_json::<CDBResponse>(cdb_resp)
+        .map_err(|msg| FnError::new(ErrorCode::CloudantError, msg))?;
+
+    // Build output struct
+    let mut body = json!({
+        "err": false,
+        "msg": "view execution complete!",
+        "data": cdb_data
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}
@@ -0,0 +1,51 @@
+use serde_json::{self as sj};
+use ureq::json as json;
+use shared::{authenticate, ErrorCode, FnError, get_apikey_details, iam_apikey, iam_endpoint, query_flag, read_request, respond_result, Timings};
+
+
+fn main() {
+    // The wsk function output, or its error response, is pushed to stdout
+    respond_result(run())
+}
+
+fn run() -> Result<sj::Value, FnError> {
+    
+    // Time spent on IAM, reported when `timings=true`
+    let mut timings = Timings::start();
+
+    // Parse and check the raw HTTP request, see `shared::prelude`
+    let i = read_request()?;
+
+    // The identity lookup is done for the apikey itself
+    let apikey = match iam_apikey(&i) {
+        Some(apikey) => apikey,
+        None => {
+            // No apikey in the payload nor in IAM_APIKEY
+            // Return error message
+            return Err(FnError::new(ErrorCode::ConfigError, "Missing iam_apikey: whoami looks up the identity of an apikey"))
+        }
+    };
+
+    // Request IAM token from IBM Cloud, then the apikey's identity
+    let iam_url = iam_endpoint().map_err(FnError::Iam)?;
+    let details = timings.iam(|| authenticate(&iam_url, Some(&apikey))
+        .and_then(|token| get_apikey_details(&iam_url, &token, &apikey)))
+        .map_err(FnError::Iam)?;
+
+    // Build output struct, the token and apikey never leave the function
+    let mut body = json!({
+        "err": false,
+        "msg": "whoami execution complete!",
+        "identity": {
+            "subject": details.iam_id,
+            "account_id": details.account_id,
+            "apikey_id": details.id,
+            "apikey_name": details.name
+        }
+    });
+    if query_flag(&i.__ow_query, "timings") {
+        body["timings"] = timings.to_json();
+    }
+
+    Ok(body)
+}